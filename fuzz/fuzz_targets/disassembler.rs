@@ -0,0 +1,24 @@
+//! Disassembles arbitrary bytes, checking that neither a listing nor a
+//! single instruction panics and that the listing has a line per word.
+//! The seed corpus in `corpus/disassembler` holds the ROMs of the
+//! repository.
+//!
+//! Run with `cargo fuzz run disassembler` from the repository root.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chip8;
+
+use chip8::disassembler::{Instruction, Listing};
+use chip8::interpreter::MemoryLayout;
+
+fuzz_target!(|data: &[u8]| {
+    let listing = Listing::new(data, MemoryLayout::default()).to_string();
+    assert_eq!(listing.lines().count(), data.len().div_ceil(2));
+
+    for word in data.chunks(2) {
+        let instr = (u16::from(word[0]) << 8) | u16::from(*word.get(1).unwrap_or(&0));
+        let _ = Instruction(instr).to_string();
+    }
+});
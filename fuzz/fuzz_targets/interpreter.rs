@@ -0,0 +1,101 @@
+//! Runs arbitrary programs with arbitrary options and keys, checking that
+//! the interpreter never panics and that a fault stops it for good.
+//!
+//! An input is laid out as:
+//!
+//! - 1 byte of quirks, bit N enabling `Quirks::NAMES[N]`
+//! - 1 byte with the tickrate
+//! - 1 byte with the random seed
+//! - 1 byte with the memory size, 4 KB shifted left by it modulo 5
+//! - 1 byte with the load address, in pages of 256 bytes
+//! - 1 byte with the small font address, in units of 16 bytes, the big
+//!   font following it
+//! - 1 byte picking one of `FONTS`
+//! - 1 byte with the number of key frames
+//! - 2 bytes per key frame, little endian, bit N holding down key N
+//! - the program, loaded at the load address
+//!
+//! Inputs with a layout the program or the fonts do not fit in are
+//! skipped. The key frames are fed over and over for `TICKS` ticks. The
+//! seed corpus in `corpus/interpreter` holds the ROMs of the repository
+//! behind a header with the default options and no keys, and Brix with the
+//! VIP timing and a key tapped.
+//!
+//! Run with `cargo fuzz run interpreter` from the repository root.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chip8;
+
+use chip8::font::SMALL_SIZE;
+use chip8::interpreter::{Interpreter, MemoryLayout, Options, Quirks};
+
+const TICKS: usize = 1000;
+
+const HEADER_SIZE: usize = 8;
+
+const FONTS: [&str; 5] = ["default", "vip", "dream6800", "eti660", "fish"];
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+    let mut quirks = Quirks::default();
+    for (bit, name) in Quirks::NAMES.iter().enumerate() {
+        quirks.set(name, data[0] & (1 << bit) != 0);
+    }
+    let font_address = data[5] as usize * 16;
+    let layout = MemoryLayout {
+        size: 0x1000 << (data[3] % 5),
+        load_address: data[4] as usize * 0x100,
+        font_address,
+        big_font_address: font_address + SMALL_SIZE,
+    };
+    if layout.check().is_err() {
+        return;
+    }
+    let options = Options {
+        quirks,
+        tickrate: u32::from(data[1]),
+        seed: u64::from(data[2]),
+        layout,
+        font: FONTS[data[6] as usize % FONTS.len()].parse().unwrap(),
+    };
+
+    let frames = data[7] as usize;
+    if data.len() < HEADER_SIZE + frames * 2 {
+        return;
+    }
+    let keys = data[HEADER_SIZE..HEADER_SIZE + frames * 2]
+        .chunks(2)
+        .map(|pair| {
+            let mask = u16::from(pair[0]) | (u16::from(pair[1]) << 8);
+            let mut keys = [false; 16];
+            for (key, held) in keys.iter_mut().enumerate() {
+                *held = mask & (1 << key) != 0;
+            }
+            keys
+        })
+        .collect::<Vec<_>>();
+    let program = &data[HEADER_SIZE + frames * 2..];
+
+    let mut interpreter = Interpreter::with_options(options);
+    if interpreter.load(program).is_err() {
+        return;
+    }
+
+    for tick in 0..TICKS {
+        let held = keys.get(tick % keys.len().max(1)).cloned();
+        let fault = match interpreter.tick(held.unwrap_or([false; 16])) {
+            Ok(_) => continue,
+            Err(fault) => fault,
+        };
+        // the faulting instruction was not run, so it faults again
+        let snapshot = interpreter.snapshot();
+        assert_eq!(interpreter.tick([false; 16]).err(), Some(fault));
+        assert_eq!(interpreter.snapshot().memory, snapshot.memory);
+        assert_eq!(interpreter.snapshot().pc, snapshot.pc);
+        break;
+    }
+});
@@ -0,0 +1,71 @@
+//! Runs arbitrary programs on a VIP with an arbitrary interpreter and
+//! monitor ROM, checking that neither the CDP1802 nor the board around it
+//! ever panics, and that every tick is a frame that does not fault.
+//!
+//! An input is laid out as:
+//!
+//! - 1 byte with the number of key frames
+//! - 2 bytes per key frame, little endian, bit N holding down key N
+//! - 2 bytes with the size of the interpreter, little endian
+//! - the interpreter, loaded at 0x0000
+//! - `ROM_SIZE` bytes of monitor ROM
+//! - the program, loaded at 0x200
+//!
+//! Inputs whose ROMs the VIP refuses are skipped. The key frames are fed
+//! over and over for `FRAMES` frames. The seed corpus in `corpus/vip` holds
+//! Brix behind a small monitor that shows the display and beeps.
+//!
+//! Run with `cargo fuzz run vip` from the repository root.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chip8;
+
+use chip8::interpreter::Backend;
+use chip8::vip::{Vip, ROM_SIZE};
+
+const FRAMES: usize = 120;
+
+fuzz_target!(|data: &[u8]| {
+    let frames = match data.first() {
+        Some(&frames) => frames as usize,
+        None => return,
+    };
+    let data = &data[1..];
+    if data.len() < frames * 2 + 2 {
+        return;
+    }
+    let keys = data[..frames * 2]
+        .chunks(2)
+        .map(|pair| {
+            let mask = u16::from(pair[0]) | (u16::from(pair[1]) << 8);
+            let mut keys = [false; 16];
+            for (key, held) in keys.iter_mut().enumerate() {
+                *held = mask & (1 << key) != 0;
+            }
+            keys
+        })
+        .collect::<Vec<_>>();
+    let data = &data[frames * 2..];
+    let size = u16::from(data[0]) as usize | (data[1] as usize) << 8;
+    let data = &data[2..];
+    if data.len() < size + ROM_SIZE {
+        return;
+    }
+    let (interpreter, data) = data.split_at(size);
+    let (monitor, program) = data.split_at(ROM_SIZE);
+
+    let mut vip = match Vip::new(interpreter, monitor) {
+        Ok(vip) => vip,
+        Err(_) => return,
+    };
+    if vip.load(program).is_err() {
+        return;
+    }
+    for frame in 0..FRAMES {
+        let held = keys.get(frame % keys.len().max(1)).cloned();
+        let state = vip.tick(held.unwrap_or([false; 16])).unwrap();
+        assert!(state.end_of_frame);
+    }
+});
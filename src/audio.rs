@@ -1,12 +1,133 @@
 use sdl2;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use config::Config;
+use frontend::{Beeper, Hotkey};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    /// White noise, sampled twice per period so that it still follows the
+    /// frequency.
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "unknown waveform `{}` (expected square, sine, triangle or noise)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The beep, as set in the `[audio]` config section.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    /// In Hz.
+    pub frequency: f32,
+    /// From 0 to 1.
+    pub volume: f32,
+    pub waveform: Waveform,
+    /// Fade in and out times in milliseconds, which keep the beep from
+    /// clicking.
+    pub attack: f32,
+    pub release: f32,
+    pub muted: bool,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Tone {
+            frequency: 240.0,
+            volume: 0.5,
+            waveform: Waveform::Square,
+            attack: 5.0,
+            release: 5.0,
+            muted: false,
+        }
+    }
+}
+
+impl Tone {
+    /// Reads the `[audio]` config sections, per-ROM sections winning over
+    /// the global one.
+    pub fn from_config(config: &Config, rom_name: &str, rom_hash: &str) -> io::Result<Tone> {
+        let mut tone = Tone::default();
+        for entry in config.entries("audio", rom_name, rom_hash) {
+            let number = |min: f32, max: f32| match entry.value.parse::<f32>() {
+                Ok(v) if v >= min && v <= max => Ok(v),
+                _ => Err(config.error(
+                    entry,
+                    &format!(
+                        "invalid {} `{}` (expected {} to {})",
+                        entry.key, entry.value, min, max
+                    ),
+                )),
+            };
+            match entry.key.as_str() {
+                "frequency" => tone.frequency = number(20.0, 20000.0)?,
+                "volume" => tone.volume = number(0.0, 1.0)?,
+                "waveform" => {
+                    tone.waveform = entry
+                        .value
+                        .parse()
+                        .map_err(|e: String| config.error(entry, &e))?
+                }
+                "attack" => tone.attack = number(0.0, 1000.0)?,
+                "release" => tone.release = number(0.0, 1000.0)?,
+                "muted" => {
+                    tone.muted = match entry.value.as_str() {
+                        "true" | "yes" | "on" => true,
+                        "false" | "no" | "off" => false,
+                        _ => return Err(config.error(entry, "expected true or false")),
+                    }
+                }
+                _ => {
+                    return Err(
+                        config.error(entry, &format!("unknown audio setting `{}`", entry.key))
+                    )
+                }
+            }
+        }
+        Ok(tone)
+    }
+}
 
 pub struct Audio {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Oscillator>,
+    rate: u32,
+    on: bool,
+    muted: bool,
 }
 
 impl Audio {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+    pub fn new(sdl_context: &sdl2::Sdl, tone: Tone) -> Self {
         let audio_system = sdl_context.audio().unwrap();
 
         let desired_spec = AudioSpecDesired {
@@ -16,39 +137,158 @@ impl Audio {
         };
 
         let device = audio_system
-            .open_playback(None, &desired_spec, |spec| SquareWave {
-                phase_inc: 240.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.5,
+            .open_playback(None, &desired_spec, |spec| {
+                Oscillator::new(tone, spec.freq as u32)
             })
             .unwrap();
+        let rate = device.spec().freq as u32;
+        // the device plays all the time, silence included, so that the
+        // envelope can fade the beep in and out
+        device.resume();
+
+        Audio {
+            device,
+            rate,
+            on: false,
+            muted: tone.muted,
+        }
+    }
+
+    fn update(&mut self) {
+        self.device.lock().set_gate(self.on && !self.muted);
+    }
+
+    /// Switches to another beep, e.g. that of another ROM. The mute hotkey
+    /// keeps its state.
+    pub fn set_tone(&mut self, tone: Tone) {
+        *self.device.lock() = Oscillator::new(tone, self.rate);
+        self.update();
+    }
 
-        Audio { device }
+    pub fn start_beep(&mut self) {
+        self.on = true;
+        self.update();
     }
 
-    pub fn start_beep(&self) {
-        self.device.resume();
+    pub fn stop_beep(&mut self) {
+        self.on = false;
+        self.update();
+    }
+}
+
+impl Beeper for Audio {
+    fn set(&mut self, on: bool, _pattern: Option<&[u8; 16]>) {
+        if on != self.on {
+            if on {
+                self.start_beep();
+            } else {
+                self.stop_beep();
+            }
+        }
     }
 
-    pub fn stop_beep(&self) {
-        self.device.pause();
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        if hotkey == Hotkey::Mute {
+            self.muted = !self.muted;
+            self.update();
+        }
     }
 }
 
-struct SquareWave {
+/// Generates the beep, independently of any audio device, so that it can
+/// be played through SDL or rendered to a file.
+pub struct Oscillator {
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    waveform: Waveform,
+    attack_step: f32,
+    release_step: f32,
+    /// Envelope level from 0 to 1.
+    level: f32,
+    /// Whether the beep should be playing.
+    gate: bool,
+    noise: f32,
+    seed: u32,
+}
+
+impl Oscillator {
+    /// An oscillator producing `rate` samples per second.
+    pub fn new(tone: Tone, rate: u32) -> Self {
+        let rate = rate as f32;
+        // how much the envelope moves per sample to go from silent to full
+        // volume, or back, in the given milliseconds
+        let step = |ms: f32| {
+            if ms > 0.0 {
+                1000.0 / (ms * rate)
+            } else {
+                1.0
+            }
+        };
+        Oscillator {
+            phase_inc: tone.frequency / rate,
+            phase: 0.0,
+            volume: tone.volume,
+            waveform: tone.waveform,
+            attack_step: step(tone.attack),
+            release_step: step(tone.release),
+            level: 0.0,
+            gate: false,
+            noise: 0.0,
+            seed: 0x2545_F491,
+        }
+    }
+
+    /// Fades the beep in or out.
+    pub fn set_gate(&mut self, on: bool) {
+        self.gate = on;
+    }
+
+    fn sample(&mut self) -> f32 {
+        match self.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * 2.0 * ::std::f32::consts::PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    fn advance(&mut self) {
+        let before = self.phase;
+        self.phase = (self.phase + self.phase_inc) % 1.0;
+        if (before < 0.5) != (self.phase < 0.5) {
+            // xorshift32
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            self.noise = (self.seed as f32 / u32::MAX as f32) * 2.0 - 1.0;
+        }
+    }
+
+    /// Writes the next samples, from -1 to 1.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for v in out.iter_mut() {
+            self.level = if self.gate {
+                (self.level + self.attack_step).min(1.0)
+            } else {
+                (self.level - self.release_step).max(0.0)
+            };
+            *v = self.volume * self.level * self.sample();
+            self.advance();
+        }
+    }
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Oscillator {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        out.iter_mut().for_each(|v| {
-            let next_val = if self.phase < 0.5 { 1.0 } else { -1.0 };
-            *v = self.volume * next_val;
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        });
+        self.fill(out);
     }
 }
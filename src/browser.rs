@@ -0,0 +1,192 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use database::{Database, RomInfo};
+use keyboard::Keyboard;
+use movie;
+use screen::Screen;
+
+/// File extensions of the ROMs listed, by platform.
+pub const ROM_EXTENSIONS: [(&str, &str); 4] = [
+    ("ch8", "CHIP-8"),
+    ("c8", "CHIP-8"),
+    ("sc8", "SUPER-CHIP"),
+    ("xo8", "XO-CHIP"),
+];
+
+/// Number of ROMs shown at once.
+const PAGE: usize = 10;
+
+/// How often the menu polls the keyboard.
+const POLL_INTERVAL: Duration = Duration::from_millis(15);
+
+/// Keys that move through the menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Select,
+    Quit,
+    /// Not a key, sent when the window needs to be drawn again.
+    Redraw,
+}
+
+/// A ROM found in the browsed directory.
+#[derive(Clone, Debug)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub platform: &'static str,
+    /// First line of a `.txt` file next to the ROM with the same name, as
+    /// ROM packs often ship them.
+    pub description: Option<String>,
+    pub info: Option<RomInfo>,
+}
+
+impl RomEntry {
+    fn new(path: PathBuf, database: &Database) -> Option<RomEntry> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        let platform = ROM_EXTENSIONS.iter().find(|e| e.0 == extension)?.1;
+        let metadata = fs::metadata(&path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        let description = fs::read_to_string(path.with_extension("txt"))
+            .ok()
+            .and_then(|text| {
+                text.lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .map(str::to_string)
+            });
+        let info = fs::read(&path)
+            .ok()
+            .and_then(|program| database.lookup(&movie::rom_hash(&program)).cloned());
+        Some(RomEntry {
+            name: path.file_name()?.to_string_lossy().into_owned(),
+            path,
+            size: metadata.len(),
+            platform,
+            description,
+            info,
+        })
+    }
+}
+
+/// The ROMs in `dir`, sorted by name, with what `database` knows about
+/// them.
+pub fn list_roms<P: AsRef<Path>>(dir: P, database: &Database) -> io::Result<Vec<RomEntry>> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Some(rom) = RomEntry::new(entry?.path(), database) {
+            roms.push(rom);
+        }
+    }
+    roms.sort_by_key(|rom| rom.name.to_lowercase());
+    Ok(roms)
+}
+
+/// What the keys do, as the database tells, e.g. `up 5, down 8`.
+pub fn key_hints(info: &RomInfo) -> String {
+    info.keys
+        .iter()
+        .map(|&(ref name, key)| format!("{} {:X}", name, key))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A line of the menu, highlighted when it is the selection.
+pub type MenuLine = (String, bool);
+
+/// Lists the ROMs of a directory in the window and lets the player pick
+/// one with the arrow keys and Enter.
+pub struct Browser {
+    roms: Vec<RomEntry>,
+    selected: usize,
+    /// First ROM shown.
+    top: usize,
+}
+
+impl Browser {
+    pub fn new(roms: Vec<RomEntry>) -> Self {
+        Browser {
+            roms,
+            selected: 0,
+            top: 0,
+        }
+    }
+
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.roms.get(self.selected)
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        let last = self.roms.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + delta).max(0).min(last) as usize;
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + PAGE {
+            self.top = self.selected + 1 - PAGE;
+        }
+    }
+
+    /// The menu as lines of text: a page of ROMs and the details of the
+    /// selected one below it.
+    pub fn lines(&self) -> Vec<MenuLine> {
+        let mut lines = vec![(format!("{} roms", self.roms.len()), false)];
+        lines.push((String::new(), false));
+        for idx in self.top..self.top + PAGE {
+            let name = self.roms.get(idx).map_or("", |rom| rom.name.as_str());
+            lines.push((name.to_string(), idx == self.selected));
+        }
+        lines.push((String::new(), false));
+        if let Some(rom) = self.selected() {
+            match rom.info {
+                Some(ref info) => {
+                    lines.push((info.to_string(), false));
+                    lines.push((key_hints(info), false));
+                }
+                None => {
+                    lines.push((format!("{}, {} bytes", rom.platform, rom.size), false));
+                    lines.push((rom.description.clone().unwrap_or_default(), false));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Shows the menu until a ROM is selected, or returns `None` when the
+    /// player quits.
+    pub fn choose(&mut self, keyboard: &mut Keyboard, screen: &mut Screen) -> Option<&RomEntry> {
+        screen.draw_menu(&self.lines());
+        loop {
+            let keys = keyboard.menu_keys();
+            if keys.is_empty() {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            for key in keys {
+                match key {
+                    MenuKey::Up => self.move_by(-1),
+                    MenuKey::Down => self.move_by(1),
+                    MenuKey::PageUp => self.move_by(-(PAGE as isize)),
+                    MenuKey::PageDown => self.move_by(PAGE as isize),
+                    MenuKey::Home => self.move_by(-(self.roms.len() as isize)),
+                    MenuKey::End => self.move_by(self.roms.len() as isize),
+                    MenuKey::Select => return self.selected(),
+                    MenuKey::Quit => return None,
+                    MenuKey::Redraw => {}
+                }
+            }
+            screen.draw_menu(&self.lines());
+        }
+    }
+}
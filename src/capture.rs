@@ -0,0 +1,567 @@
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use filter::Frame;
+use interpreter::TIMER_RATE;
+use palette::{Palette, Rgb};
+use CHIP8_HEIGHT;
+use CHIP8_WIDTH;
+
+pub const DEFAULT_SCALE: u32 = 8;
+
+/// Largest scale whose images GIF, with its 16 bit sizes, can hold.
+pub const MAX_SCALE: u32 = u16::MAX as u32 / CHIP8_WIDTH as u32;
+
+/// Captures run on emulated time, one image per emulated 60 Hz frame, so
+/// that they stay in sync with audio rendered from the same run.
+pub const CAPTURE_RATE: u32 = TIMER_RATE;
+
+/// How captures and screenshots are drawn.
+#[derive(Clone, Debug)]
+pub struct ImageOptions {
+    pub palette: Palette,
+    /// Image pixels per CHIP-8 pixel.
+    pub scale: u32,
+}
+
+impl ImageOptions {
+    pub fn width(&self) -> u32 {
+        CHIP8_WIDTH as u32 * self.scale
+    }
+
+    pub fn height(&self) -> u32 {
+        CHIP8_HEIGHT as u32 * self.scale
+    }
+
+    /// Colors of the scaled image, row by row.
+    fn pixels(&self, frame: &Frame) -> Vec<Rgb> {
+        let scale = self.scale as usize;
+        let mut pixels = Vec::with_capacity((self.width() * self.height()) as usize);
+        for y in 0..CHIP8_HEIGHT {
+            let row = (0..CHIP8_WIDTH)
+                .flat_map(|x| ::std::iter::repeat_n(frame.color(&self.palette, x, y), scale))
+                .collect::<Vec<_>>();
+            for _ in 0..scale {
+                pixels.extend_from_slice(&row);
+            }
+        }
+        pixels
+    }
+
+    /// The frame as raw RGB24 bytes.
+    pub fn rgb(&self, frame: &Frame) -> Vec<u8> {
+        self.pixels(frame)
+            .iter()
+            .flat_map(|&(r, g, b)| vec![r, g, b])
+            .collect()
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_u32::<BigEndian>(data.len() as u32)?;
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    out.write_all(&body)?;
+    out.write_u32::<BigEndian>(crc32(&body))
+}
+
+/// Writes an RGB PNG. The image data goes into stored deflate blocks; the
+/// files are bigger than they could be, but need no compression library.
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::new();
+    header.write_u32::<BigEndian>(width)?;
+    header.write_u32::<BigEndian>(height)?;
+    // 8 bit RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_png_chunk(out, b"IHDR", &header)?;
+
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks(width as usize * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(0xFFFF).collect::<Vec<_>>();
+    for (idx, block) in blocks.iter().enumerate() {
+        zlib.push(if idx + 1 == blocks.len() { 1 } else { 0 });
+        zlib.write_u16::<LittleEndian>(block.len() as u16)?;
+        zlib.write_u16::<LittleEndian>(!(block.len() as u16))?;
+        zlib.extend_from_slice(block);
+    }
+    zlib.write_u32::<BigEndian>(adler32(&raw))?;
+    write_png_chunk(out, b"IDAT", &zlib)?;
+
+    write_png_chunk(out, b"IEND", &[])
+}
+
+pub fn save_png<P: AsRef<Path>>(path: P, frame: &Frame, options: &ImageOptions) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_png(
+        &mut out,
+        options.width(),
+        options.height(),
+        &options.rgb(frame),
+    )?;
+    out.flush()
+}
+
+/// Saves screenshots under `<prefix>-NNN.png`, using the first number not
+/// taken yet.
+pub struct Screenshots {
+    pub prefix: String,
+    pub options: ImageOptions,
+}
+
+impl Screenshots {
+    pub fn save(&self, frame: &Frame) -> io::Result<PathBuf> {
+        let path = (1..)
+            .map(|n| PathBuf::from(format!("{}-{:03}.png", self.prefix, n)))
+            .find(|path| !path.exists())
+            .expect("ran out of screenshot names");
+        save_png(&path, frame, &self.options)?;
+        Ok(path)
+    }
+}
+
+/// Packs variable width LZW codes into GIF data sub-blocks.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.bits |= u32::from(code) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+/// LZW compresses color indices the way GIF image data expects.
+fn lzw(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    const MAX_CODES: u16 = 4096;
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    let mut out = BitWriter {
+        bytes: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    // code for each prefix code and following index, 0 when not assigned
+    // yet (0 is never assigned, it is one of the single index codes)
+    let mut table = vec![0u16; (MAX_CODES as usize) << min_code_size];
+    let slot = |prefix: u16, index: u8| ((prefix as usize) << min_code_size) | index as usize;
+    let mut next = end + 1;
+    let mut size = min_code_size + 1;
+    out.write(clear, size);
+
+    let mut current: Option<u16> = None;
+    for &index in indices {
+        let prefix = match current {
+            None => {
+                current = Some(u16::from(index));
+                continue;
+            }
+            Some(prefix) => prefix,
+        };
+        let code = table[slot(prefix, index)];
+        if code != 0 {
+            current = Some(code);
+            continue;
+        }
+
+        out.write(prefix, size);
+        // the decoder widens its codes once the next free code no longer
+        // fits, one code after the encoder assigned it
+        if next >= (1 << size) && size < 12 {
+            size += 1;
+        }
+        if next < MAX_CODES {
+            table[slot(prefix, index)] = next;
+            next += 1;
+        } else {
+            out.write(clear, size);
+            table.iter_mut().for_each(|code| *code = 0);
+            next = end + 1;
+            size = min_code_size + 1;
+        }
+        current = Some(u16::from(index));
+    }
+
+    if let Some(prefix) = current {
+        out.write(prefix, size);
+        if next >= (1 << size) && size < 12 {
+            size += 1;
+        }
+    }
+    out.write(end, size);
+    out.finish()
+}
+
+/// Writes an animated, looping GIF, one local color table per frame.
+pub struct GifWriter<W: Write> {
+    out: W,
+}
+
+/// Makes sure a GIF image can be `width` by `height` pixels.
+fn check_gif_size(width: u32, height: u32) -> io::Result<()> {
+    if width > u32::from(u16::MAX) || height > u32::from(u16::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a GIF cannot be {}x{} pixels", width, height),
+        ));
+    }
+    Ok(())
+}
+
+impl<W: Write> GifWriter<W> {
+    pub fn new(mut out: W, width: u32, height: u32) -> io::Result<Self> {
+        check_gif_size(width, height)?;
+        out.write_all(b"GIF89a")?;
+        out.write_u16::<LittleEndian>(width as u16)?;
+        out.write_u16::<LittleEndian>(height as u16)?;
+        // no global color table, background 0, square pixels
+        out.write_all(&[0x70, 0, 0])?;
+        // NETSCAPE2.0 extension, loop forever
+        out.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        Ok(GifWriter { out })
+    }
+
+    pub fn frame(&mut self, width: u32, height: u32, pixels: &[Rgb], delay: u16) -> io::Result<()> {
+        check_gif_size(width, height)?;
+        let mut colors: Vec<Rgb> = Vec::new();
+        let mut lookup: HashMap<Rgb, u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(pixels.len());
+        for &color in pixels {
+            let index = match lookup.get(&color) {
+                Some(&index) => index,
+                None if colors.len() < 256 => {
+                    colors.push(color);
+                    let index = (colors.len() - 1) as u8;
+                    lookup.insert(color, index);
+                    index
+                }
+                // more colors than a GIF frame can hold, use the closest
+                None => nearest(&colors, color),
+            };
+            indices.push(index);
+        }
+
+        // the color table holds 2^(bits) entries
+        let bits = (1..=8).find(|&bits| colors.len() <= 1 << bits).unwrap_or(8);
+        colors.resize(1 << bits, (0, 0, 0));
+
+        // graphic control extension with the frame delay
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x00])?;
+        self.out.write_u16::<LittleEndian>(delay)?;
+        self.out.write_all(&[0x00, 0x00])?;
+
+        // image descriptor with a local color table
+        self.out.write_u8(0x2C)?;
+        self.out.write_u16::<LittleEndian>(0)?;
+        self.out.write_u16::<LittleEndian>(0)?;
+        self.out.write_u16::<LittleEndian>(width as u16)?;
+        self.out.write_u16::<LittleEndian>(height as u16)?;
+        self.out.write_u8(0x80 | (bits - 1) as u8)?;
+        for &(r, g, b) in &colors {
+            self.out.write_all(&[r, g, b])?;
+        }
+
+        let min_code_size = bits.max(2);
+        self.out.write_u8(min_code_size as u8)?;
+        for block in lzw(&indices, min_code_size).chunks(255) {
+            self.out.write_u8(block.len() as u8)?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_u8(0)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.write_u8(0x3B)?;
+        self.out.flush()
+    }
+}
+
+fn nearest(colors: &[Rgb], (r, g, b): Rgb) -> u8 {
+    let distance = |&(cr, cg, cb): &Rgb| {
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+    (0..colors.len())
+        .min_by_key(|&idx| distance(&colors[idx]))
+        .unwrap_or(0) as u8
+}
+
+enum Sink {
+    /// The last frame is held back until a different one comes in, so that
+    /// a still picture becomes a single GIF frame with a long delay.
+    Gif(GifWriter<BufWriter<File>>, Option<(Box<Frame>, u16)>),
+    /// Plain RGB24 frames, e.g. for
+    /// `ffmpeg -f rawvideo -pix_fmt rgb24 -s 512x256 -r 60 -i FILE out.mp4`.
+    Raw(BufWriter<File>),
+}
+
+/// Continuous capture of the presented frames, to an animated GIF when the
+/// file name ends in `.gif` and to raw RGB24 frames otherwise.
+pub struct Capture {
+    sink: Sink,
+    options: ImageOptions,
+    frames: u32,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P, options: ImageOptions) -> io::Result<Self> {
+        let is_gif = path
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+        let out = BufWriter::new(File::create(path)?);
+        let sink = if is_gif {
+            Sink::Gif(
+                GifWriter::new(out, options.width(), options.height())?,
+                None,
+            )
+        } else {
+            Sink::Raw(out)
+        };
+        Ok(Capture {
+            sink,
+            options,
+            frames: 0,
+        })
+    }
+
+    pub fn frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let (width, height) = (self.options.width(), self.options.height());
+        // GIF delays are in hundredths of a second, which 1/60 s is not a
+        // whole number of; rounding the time of every frame instead of its
+        // length keeps the clip from drifting
+        let rate = u64::from(CAPTURE_RATE);
+        let time = |frame: u32| (u64::from(frame) * 100 + rate / 2) / rate;
+        let delay = (time(self.frames + 1) - time(self.frames)) as u16;
+        self.frames += 1;
+        match self.sink {
+            Sink::Gif(ref mut gif, ref mut pending) => {
+                if let Some((ref last, ref mut last_delay)) = *pending {
+                    if **last == *frame && *last_delay < u16::MAX - delay {
+                        *last_delay += delay;
+                        return Ok(());
+                    }
+                }
+                match pending.replace((Box::new(*frame), delay)) {
+                    Some((last, last_delay)) => {
+                        gif.frame(width, height, &self.options.pixels(&last), last_delay)
+                    }
+                    None => Ok(()),
+                }
+            }
+            Sink::Raw(ref mut out) => out.write_all(&self.options.rgb(frame)),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Gif(mut gif, pending) => {
+                if let Some((last, delay)) = pending {
+                    let (width, height) = (self.options.width(), self.options.height());
+                    gif.frame(width, height, &self.options.pixels(&last), delay)?;
+                }
+                gif.finish()
+            }
+            Sink::Raw(mut out) => out.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes GIF LZW data, returning the indices and the number of clear
+    /// codes read.
+    fn unlzw(data: &[u8], min_code_size: u32) -> (Vec<u8>, usize) {
+        let clear = 1usize << min_code_size;
+        let end = clear + 1;
+        let mut table = (0..clear).map(|idx| vec![idx as u8]).collect::<Vec<_>>();
+        table.extend(vec![Vec::new(), Vec::new()]);
+        let mut size = min_code_size + 1;
+        let (mut pos, mut clears) = (0, 0);
+        let mut prev: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            let code = (0..size).fold(0, |code, bit| {
+                let byte = data[(pos + bit as usize) / 8];
+                code | usize::from((byte >> ((pos + bit as usize) % 8)) & 1) << bit
+            });
+            pos += size as usize;
+            if code == clear {
+                table.truncate(end + 1);
+                size = min_code_size + 1;
+                prev = None;
+                clears += 1;
+                continue;
+            }
+            if code == end {
+                break;
+            }
+            let entry = match table.get(code) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let mut entry = prev.clone().unwrap();
+                    entry.push(entry[0]);
+                    entry
+                }
+            };
+            if let Some(mut added) = prev.take() {
+                if table.len() < 4096 {
+                    added.push(entry[0]);
+                    table.push(added);
+                    if table.len() == 1 << size && size < 12 {
+                        size += 1;
+                    }
+                }
+            }
+            out.extend_from_slice(&entry);
+            prev = Some(entry);
+        }
+        assert_eq!(pos.div_ceil(8), data.len());
+        (out, clears)
+    }
+
+    #[test]
+    fn lzw_matches_known_bytes() {
+        // clear, 0, 6, 0 in 3 bits, then end in 4 bits once code 8 is due
+        assert_eq!(lzw(&[0, 0, 0, 0], 2), vec![0x84, 0x51]);
+    }
+
+    #[test]
+    fn lzw_widens_codes_at_powers_of_two() {
+        // noise keeps adding codes, past every 2^n up to the 2^12 limit,
+        // where the table starts over
+        let noise = |count: usize, bits: u32| {
+            let mut state = 0x2545_F491u32;
+            (0..count)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state >> (32 - bits)) as u8
+                })
+                .collect::<Vec<_>>()
+        };
+        let indices = noise(60_000, 2);
+        let (decoded, clears) = unlzw(&lzw(&indices, 2), 2);
+        assert_eq!(decoded, indices);
+        assert!(clears > 1);
+
+        for &bits in &[2, 3, 8] {
+            let indices = noise(5000, bits);
+            assert_eq!(unlzw(&lzw(&indices, bits), bits).0, indices);
+        }
+    }
+
+    #[test]
+    fn gif_sizes_fit_in_16_bits() {
+        let (width, height) = (
+            MAX_SCALE * CHIP8_WIDTH as u32,
+            MAX_SCALE * CHIP8_HEIGHT as u32,
+        );
+        assert!(GifWriter::new(Vec::new(), width, height).is_ok());
+        assert!(GifWriter::new(Vec::new(), 0x10000, 1).is_err());
+        let mut gif = GifWriter::new(Vec::new(), 1, 1).unwrap();
+        assert!(gif.frame(1, 0x10000, &[], 1).is_err());
+    }
+
+    #[test]
+    fn gif_frames_start_with_a_clear_code() {
+        let mut out = Vec::new();
+        let mut gif = GifWriter::new(&mut out, 2, 1).unwrap();
+        gif.frame(2, 1, &[(0, 0, 0), (255, 255, 255)], 5).unwrap();
+        gif.finish().unwrap();
+
+        assert_eq!(&out[..10], b"GIF89a\x02\x00\x01\x00");
+        assert_eq!(out[out.len() - 1], 0x3B);
+        // logical screen, NETSCAPE2.0, graphic control and image descriptor
+        let table = 13 + 19 + 8 + 10;
+        assert_eq!(&out[table - 10..table], b"\x2C\0\0\0\0\x02\0\x01\0\x80");
+        assert_eq!(&out[table..table + 6], &[0, 0, 0, 255, 255, 255]);
+        let (min_code_size, len) = (out[table + 6], out[table + 7] as usize);
+        assert_eq!(min_code_size, 2);
+        let data = &out[table + 8..table + 8 + len];
+        assert_eq!(data[0] & 0b111, 4);
+        assert_eq!(unlzw(data, 2), (vec![0, 1], 1));
+        assert_eq!(out[table + 8 + len], 0);
+    }
+
+    #[test]
+    fn png_checksums_match_known_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn png_of_a_single_pixel() {
+        let mut out = Vec::new();
+        write_png(&mut out, 1, 1, &[0x12, 0x34, 0x56]).unwrap();
+
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(
+            &out[8..33],
+            &b"\0\0\0\x0DIHDR\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0\x90\x77\x53\xDE"[..]
+        );
+        // a single stored block holding the filter byte and the pixel
+        let raw = [0, 0x12, 0x34, 0x56];
+        let mut zlib = vec![0x78, 0x01, 0x01, 0x04, 0x00, 0xFB, 0xFF];
+        zlib.extend_from_slice(&raw);
+        zlib.write_u32::<BigEndian>(adler32(&raw)).unwrap();
+        assert_eq!(&out[33..37], &[0, 0, 0, zlib.len() as u8]);
+        assert_eq!(&out[37..41], b"IDAT");
+        assert_eq!(&out[41..41 + zlib.len()], &zlib[..]);
+        let end = 41 + zlib.len() + 4;
+        assert_eq!(&out[end..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+}
@@ -0,0 +1,553 @@
+/// What the CPU is wired to: memory, the I/O ports and the EF flag lines.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    /// OUT 1-7 put `value` on the data bus for `port`.
+    fn output(&mut self, port: u8, value: u8);
+    /// INP 1-7 read the data bus from `port`.
+    fn input(&mut self, port: u8) -> u8;
+    /// Whether the EF1-EF4 line `flag` is asserted.
+    fn flag(&mut self, flag: u8) -> bool;
+}
+
+/// Machine cycles of the instructions other than long branches and skips.
+const SHORT_CYCLES: u32 = 2;
+
+/// Machine cycles of the 0xCN long branch and skip instructions.
+const LONG_CYCLES: u32 = 3;
+
+/// An RCA CDP1802 CPU. Timing is counted in machine cycles of 8 clocks.
+#[derive(Clone, Debug)]
+pub struct Cdp1802 {
+    /// The 16 scratchpad registers.
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    /// Number of the program counter register.
+    pub p: u8,
+    /// Number of the data pointer register.
+    pub x: u8,
+    /// X and P saved by an interrupt or MARK.
+    pub t: u8,
+    /// Interrupts are enabled.
+    pub ie: bool,
+    pub q: bool,
+    /// Stopped by IDL until the next interrupt or DMA.
+    pub idle: bool,
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Self {
+        Cdp1802 {
+            r: [0; 16],
+            d: 0,
+            df: false,
+            p: 0,
+            x: 0,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+}
+
+impl Cdp1802 {
+    pub fn new() -> Self {
+        Cdp1802::default()
+    }
+
+    /// What the reset line does: R0, P, X and Q are cleared and interrupts
+    /// enabled. The other registers keep their values.
+    pub fn reset(&mut self) {
+        self.r[0] = 0;
+        self.p = 0;
+        self.x = 0;
+        self.q = false;
+        self.ie = true;
+        self.idle = false;
+    }
+
+    /// Services an interrupt if they are enabled, returning the machine
+    /// cycles taken.
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = (self.x << 4) | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    /// A DMA out cycle: the byte at R0 goes to the device and R0 moves on.
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let pc = self.p as usize;
+        let value = bus.read(self.r[pc]);
+        self.r[pc] = self.r[pc].wrapping_add(1);
+        value
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn inc_x(&mut self) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(1);
+    }
+
+    fn set_lo(&mut self, n: usize, value: u8) {
+        self.r[n] = (self.r[n] & 0xFF00) | u16::from(value);
+    }
+
+    fn set_hi(&mut self, n: usize, value: u8) {
+        self.r[n] = (self.r[n] & 0x00FF) | (u16::from(value) << 8);
+    }
+
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = u16::from(a) + u16::from(b) + u16::from(carry);
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    /// `a - b`, with DF set when there is no borrow like on the 1802.
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let diff = i16::from(a) - i16::from(b) - i16::from(borrow);
+        self.d = diff as u8;
+        self.df = diff >= 0;
+    }
+
+    /// Whether the condition of a branch or skip with low nibble `n` holds,
+    /// the upper half of the nibbles testing the opposite.
+    fn condition<B: Bus>(&mut self, n: u8, bus: &mut B) -> bool {
+        let result = match n & 7 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            flag => bus.flag(flag - 3),
+        };
+        if n & 8 == 0 {
+            result
+        } else {
+            !result
+        }
+    }
+
+    /// Runs one instruction, returning the machine cycles it took. An idle
+    /// CPU only lets a cycle pass.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0xF) as usize;
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let target = self.fetch(bus);
+                // NBR (0x38) is the one that never branches
+                if n != 8 && self.condition(n as u8, bus) {
+                    let pc = self.p as usize;
+                    let target = (self.r[pc].wrapping_sub(1) & 0xFF00) | u16::from(target);
+                    self.r[pc] = target;
+                }
+            }
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 if n == 0 => self.inc_x(),
+            0x6 if n < 8 => {
+                let value = bus.read(self.rx());
+                bus.output(n as u8, value);
+                self.inc_x();
+            }
+            // 0x68 is only an instruction on the CDP1804
+            0x6 if n == 8 => {}
+            0x6 => {
+                let value = bus.input(n as u8 - 8);
+                bus.write(self.rx(), value);
+                self.d = value;
+            }
+            0x7 => self.misc(n, bus),
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => {
+                let d = self.d;
+                self.set_lo(n, d);
+            }
+            0xB => {
+                let d = self.d;
+                self.set_hi(n, d);
+            }
+            0xC => {
+                self.long(n as u8, bus);
+                return LONG_CYCLES;
+            }
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            _ => self.alu(n, bus),
+        }
+        SHORT_CYCLES
+    }
+
+    fn misc<B: Bus>(&mut self, n: usize, bus: &mut B) {
+        match n {
+            // RET and DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.rx());
+                self.inc_x();
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = n == 0;
+            }
+            0x2 => {
+                self.d = bus.read(self.rx());
+                self.inc_x();
+            }
+            0x3 => {
+                bus.write(self.rx(), self.d);
+                let x = self.x as usize;
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            0x4 => {
+                let (m, d, df) = (bus.read(self.rx()), self.d, self.df);
+                self.add(m, d, df);
+            }
+            0x5 => {
+                let (m, d, df) = (bus.read(self.rx()), self.d, self.df);
+                self.subtract(m, d, !df);
+            }
+            // SHRC
+            0x6 => {
+                let carry = self.d & 1 != 0;
+                self.d = (self.d >> 1) | if self.df { 0x80 } else { 0 };
+                self.df = carry;
+            }
+            0x7 => {
+                let (m, d, df) = (bus.read(self.rx()), self.d, self.df);
+                self.subtract(d, m, !df);
+            }
+            0x8 => bus.write(self.rx(), self.t),
+            // MARK
+            0x9 => {
+                self.t = (self.x << 4) | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            0xA => self.q = false,
+            0xB => self.q = true,
+            0xC => {
+                let (m, d, df) = (self.fetch(bus), self.d, self.df);
+                self.add(m, d, df);
+            }
+            0xD => {
+                let (m, d, df) = (self.fetch(bus), self.d, self.df);
+                self.subtract(m, d, !df);
+            }
+            // SHLC
+            0xE => {
+                let carry = self.d & 0x80 != 0;
+                self.d = (self.d << 1) | u8::from(self.df);
+                self.df = carry;
+            }
+            _ => {
+                let (m, d, df) = (self.fetch(bus), self.d, self.df);
+                self.subtract(d, m, !df);
+            }
+        }
+    }
+
+    /// The 0xFN instructions, which take their operand at R(X), or at R(P)
+    /// for the immediate ones from 0xF8 on.
+    fn alu<B: Bus>(&mut self, n: usize, bus: &mut B) {
+        let operand = match n {
+            // SHR and SHL have no operand
+            0x6 | 0xE => 0,
+            0x0..=0x7 => bus.read(self.rx()),
+            _ => self.fetch(bus),
+        };
+        let d = self.d;
+        match n & 7 {
+            0 => self.d = operand,
+            1 => self.d |= operand,
+            2 => self.d &= operand,
+            3 => self.d ^= operand,
+            4 => self.add(operand, d, false),
+            5 => self.subtract(operand, d, false),
+            6 if n == 6 => {
+                self.df = d & 1 != 0;
+                self.d = d >> 1;
+            }
+            6 => {
+                self.df = d & 0x80 != 0;
+                self.d = d << 1;
+            }
+            _ => self.subtract(d, operand, false),
+        }
+    }
+
+    /// Long branches, which load both bytes of R(P), and long skips, which
+    /// step over the two bytes after them.
+    fn long<B: Bus>(&mut self, n: u8, bus: &mut B) {
+        let pc = self.p as usize;
+        match n {
+            // NOP
+            0x4 => {}
+            // LSIE
+            0xC => {
+                if self.ie {
+                    self.r[pc] = self.r[pc].wrapping_add(2);
+                }
+            }
+            // the skips: LSNQ, LSNZ, LSNF, LSKP and LSQ, LSZ, LSDF, whose
+            // conditions are inverted compared to the branches
+            0x5..=0x8 | 0xD..=0xF => {
+                let condition = match n & 3 {
+                    0 => true,
+                    1 => !self.q,
+                    2 => self.d != 0,
+                    _ => !self.df,
+                };
+                if condition != (n & 8 != 0) || n == 8 {
+                    self.r[pc] = self.r[pc].wrapping_add(2);
+                }
+            }
+            _ => {
+                let high = self.fetch(bus);
+                let low = self.fetch(bus);
+                if self.condition(n, bus) {
+                    self.r[pc] = (u16::from(high) << 8) | u16::from(low);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 KB of RAM with EF lines that can be set and a log of the output
+    /// ports.
+    struct TestBus {
+        ram: Vec<u8>,
+        flags: [bool; 4],
+        outputs: Vec<(u8, u8)>,
+    }
+
+    impl TestBus {
+        fn new(program: &[u8]) -> Self {
+            let mut ram = vec![0; 0x10000];
+            ram[..program.len()].copy_from_slice(program);
+            TestBus {
+                ram,
+                flags: [false; 4],
+                outputs: Vec::new(),
+            }
+        }
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.ram[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.ram[address as usize] = value;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.push((port, value));
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            0x10 + port
+        }
+
+        fn flag(&mut self, flag: u8) -> bool {
+            self.flags[flag as usize - 1]
+        }
+    }
+
+    /// Runs `program` from 0 for `steps` instructions, returning the CPU,
+    /// the bus and the machine cycles taken.
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, TestBus, u32) {
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(program);
+        let cycles = (0..steps).map(|_| cpu.step(&mut bus)).sum();
+        (cpu, bus, cycles)
+    }
+
+    #[test]
+    fn short_branches_stay_in_the_page() {
+        // BR 20
+        let (cpu, _, cycles) = run(&[0x30, 0x20], 1);
+        assert_eq!((cpu.r[0], cycles), (0x20, 2));
+        // LDI 0, BZ 30
+        assert_eq!(run(&[0xF8, 0x00, 0x32, 0x30], 2).0.r[0], 0x30);
+        // LDI 1, BZ 30 falls through
+        assert_eq!(run(&[0xF8, 0x01, 0x32, 0x30], 2).0.r[0], 0x04);
+        // LDI 1, BNZ 30
+        assert_eq!(run(&[0xF8, 0x01, 0x3A, 0x30], 2).0.r[0], 0x30);
+        // SEQ, BQ 30
+        assert_eq!(run(&[0x7B, 0x31, 0x30], 2).0.r[0], 0x30);
+        // NBR 30 skips its operand
+        assert_eq!(run(&[0x38, 0x30], 1).0.r[0], 0x02);
+
+        // from 0x1FE the target byte is fetched at 0x1FF, in the same page
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(&[]);
+        bus.ram[0x1FE] = 0x30;
+        bus.ram[0x1FF] = 0x40;
+        cpu.r[0] = 0x1FE;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[0], 0x140);
+    }
+
+    #[test]
+    fn flag_branches_test_the_ef_lines() {
+        for flag in 1..=4u8 {
+            let mut cpu = Cdp1802::new();
+            let mut bus = TestBus::new(&[0x34 + flag - 1, 0x30, 0x3C + flag - 1, 0x40]);
+            cpu.step(&mut bus);
+            assert_eq!(cpu.r[0], 0x02, "B{} without EF{}", flag, flag);
+            cpu.step(&mut bus);
+            assert_eq!(cpu.r[0], 0x40, "BN{} without EF{}", flag, flag);
+
+            let mut cpu = Cdp1802::new();
+            bus.flags[flag as usize - 1] = true;
+            cpu.step(&mut bus);
+            assert_eq!(cpu.r[0], 0x30, "B{} with EF{}", flag, flag);
+        }
+    }
+
+    #[test]
+    fn long_branches_load_the_whole_address() {
+        // LBR 1234
+        let (cpu, _, cycles) = run(&[0xC0, 0x12, 0x34], 1);
+        assert_eq!((cpu.r[0], cycles), (0x1234, 3));
+        // LDI 0, LBNZ 1234 falls through past the address
+        assert_eq!(run(&[0xF8, 0x00, 0xCA, 0x12, 0x34], 2).0.r[0], 0x05);
+        // SEQ, LBQ 1234
+        assert_eq!(run(&[0x7B, 0xC1, 0x12, 0x34], 2).0.r[0], 0x1234);
+    }
+
+    #[test]
+    fn long_skips_step_over_two_bytes() {
+        // LSKP always skips, NOP never does
+        let (cpu, _, cycles) = run(&[0xC8], 1);
+        assert_eq!((cpu.r[0], cycles), (0x03, 3));
+        let (cpu, _, cycles) = run(&[0xC4], 1);
+        assert_eq!((cpu.r[0], cycles), (0x01, 3));
+
+        // LDI 0 then LSZ, LSNZ
+        assert_eq!(run(&[0xF8, 0x00, 0xCE], 2).0.r[0], 0x05);
+        assert_eq!(run(&[0xF8, 0x00, 0xC6], 2).0.r[0], 0x03);
+        // SEQ then LSQ, LSNQ
+        assert_eq!(run(&[0x7B, 0xCD], 2).0.r[0], 0x04);
+        assert_eq!(run(&[0x7B, 0xC5], 2).0.r[0], 0x02);
+        // LDI FF, SHL sets DF, then LSDF, LSNF
+        assert_eq!(run(&[0xF8, 0xFF, 0xFE, 0xCF], 3).0.r[0], 0x06);
+        assert_eq!(run(&[0xF8, 0xFF, 0xFE, 0xC7], 3).0.r[0], 0x04);
+        // LSIE skips while interrupts are enabled
+        assert_eq!(run(&[0xCC], 1).0.r[0], 0x03);
+        let mut cpu = Cdp1802::new();
+        cpu.ie = false;
+        cpu.step(&mut TestBus::new(&[0xCC]));
+        assert_eq!(cpu.r[0], 0x01);
+    }
+
+    #[test]
+    fn mark_and_ret_save_and_restore_x_and_p() {
+        // R2 = 0100, SEX 5, MARK, SEX 2, INC 2, RET
+        let program = [0xF8, 0x01, 0xB2, 0xE5, 0x79, 0xE2, 0x12, 0x70];
+        let (cpu, bus, _) = run(&program, 4);
+        assert_eq!(cpu.t, 0x50);
+        assert_eq!(bus.ram[0x100], 0x50);
+        assert_eq!((cpu.x, cpu.p), (0, 0));
+        assert_eq!(cpu.r[2], 0xFF);
+
+        let (cpu, _, _) = run(&program, 7);
+        assert_eq!((cpu.x, cpu.p), (5, 0));
+        assert_eq!(cpu.r[2], 0x101);
+        assert!(cpu.ie);
+
+        // DIS restores the same way but leaves interrupts disabled
+        let mut program = program;
+        program[7] = 0x71;
+        let (cpu, _, _) = run(&program, 7);
+        assert_eq!((cpu.x, cpu.p), (5, 0));
+        assert!(!cpu.ie);
+    }
+
+    #[test]
+    fn input_and_output_go_through_rx() {
+        // R3 = 0010, SEX 3, OUT 2, INP 1
+        let mut program = vec![0xF8, 0x10, 0xA3, 0xE3, 0x62, 0x69];
+        program.resize(0x11, 0);
+        program[0x10] = 0xAB;
+        let (cpu, bus, _) = run(&program, 6);
+        assert_eq!(bus.outputs, vec![(2, 0xAB)]);
+        assert_eq!(cpu.r[3], 0x11);
+        assert_eq!((cpu.d, bus.ram[0x11]), (0x11, 0x11));
+    }
+
+    #[test]
+    fn dma_reads_at_r0_and_wakes_the_cpu() {
+        // IDL
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(&[0x00]);
+        cpu.step(&mut bus);
+        assert!(cpu.idle);
+        assert_eq!(cpu.step(&mut bus), 1);
+        assert_eq!(cpu.r[0], 0x01);
+
+        bus.ram[0x300] = 0xAB;
+        bus.ram[0x301] = 0xCD;
+        cpu.r[0] = 0x300;
+        assert_eq!(cpu.dma_out(&mut bus), 0xAB);
+        assert_eq!(cpu.dma_out(&mut bus), 0xCD);
+        assert_eq!(cpu.r[0], 0x302);
+        assert!(!cpu.idle);
+    }
+
+    #[test]
+    fn interrupts_save_x_and_p_and_disable_themselves() {
+        let mut cpu = Cdp1802::new();
+        cpu.x = 3;
+        cpu.p = 5;
+        cpu.idle = true;
+        assert_eq!(cpu.interrupt(), 1);
+        assert_eq!(cpu.t, 0x35);
+        assert_eq!((cpu.x, cpu.p), (2, 1));
+        assert!(!cpu.ie && !cpu.idle);
+        // a second interrupt waits until they are enabled again
+        assert_eq!(cpu.interrupt(), 0);
+        assert_eq!((cpu.x, cpu.p), (2, 1));
+
+        // SAV stores T at R(X)
+        let mut bus = TestBus::new(&[]);
+        bus.ram[0x200] = 0x78;
+        cpu.r[1] = 0x200;
+        cpu.r[2] = 0x400;
+        cpu.step(&mut bus);
+        assert_eq!(bus.ram[0x400], 0x35);
+    }
+}
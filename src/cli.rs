@@ -0,0 +1,284 @@
+use std::io;
+
+use capture;
+use filter::Filter;
+use font::Font;
+use interpreter::Quirks;
+use palette::Palette;
+use screen;
+use terminal::Glyphs;
+
+pub const USAGE: &str = "usage: chip8 [OPTIONS] [ROM | DIRECTORY]
+
+Given a directory, lists its ROMs in the window to pick one from; F1 goes
+back to the list from a game.
+
+options:
+    --config FILE     config file with bindings and display settings,
+                      default chip8.ini if it exists
+    --database FILE   programs.json of the CHIP-8 database, which sets the
+                      quirks, tickrate and colors of known ROMs, default
+                      chip-8-database.json if it exists; --quirks,
+                      --tickrate and --palette override it
+    --record FILE     record the input of this run into a movie file
+    --play FILE       play back the input stored in a movie file; movies
+                      recorded with --vip need the same --vip files
+    --headless        run without opening a window
+    --disassemble     print the program as assembly instead of running it
+    --diff QUIRKS     run the ROM a second time with these quirks instead and
+                      report the first instruction after which the runs
+                      differ; needs --play or --frames. With --vip, run it
+                      with these quirks against the VIP instead and report
+                      the first frame after which the display or the beeper
+                      differ, --frames then counting 60 Hz frames
+    --watch           restart the ROM whenever its file changes
+    --keep-state      with --watch, only patch the new program into memory,
+                      keeping the registers and the display
+    --font FONT       hex digit font: default, vip, dream6800, eti660, fish,
+                      or a file with 80 bytes of small font, optionally
+                      followed by 160 bytes of big font for FX30
+    --gdb PORT        wait for gdb on 127.0.0.1:PORT and run the ROM under its
+                      control instead of in a window; `monitor keys` and
+                      `monitor screen` stand in for the keypad and display
+    --vip FILE        run on an emulated COSMAC VIP with the original CHIP-8
+                      interpreter from FILE instead of the built-in one;
+                      quirks, fonts and memory settings do not apply
+    --vip-monitor FILE
+                      the VIP monitor ROM, which --vip needs for the display
+                      interrupt, keypad routine and font
+    --palette SPEC    palette name (mono, green, amber, lcd, high-contrast,
+                      colorblind) or a list of #rrggbb colors
+    --filter SPEC     flicker filter: none, deflicker[:FRAMES] to OR the last
+                      framebuffers together, or phosphor[:DECAY] to let
+                      pixels fade out
+    --scale N         initial window size in pixels per CHIP-8 pixel,
+                      default 20
+    --integer-scale   only scale the display by whole numbers
+    --fullscreen      start in fullscreen, F11 toggles it
+    --show-fps        show frames and instructions per second over the game
+    --screenshot FILE save the last frame as PNG when the emulator exits
+    --capture FILE    record the display to an animated GIF if FILE ends in
+                      .gif, otherwise to raw RGB24 frames for ffmpeg, one
+                      per emulated 60 Hz frame
+                      (-f rawvideo -pix_fmt rgb24 -s WxH -r 60)
+    --capture-scale N image pixels per CHIP-8 pixel for screenshots and
+                      captures, from 1 to 1023, default 8
+    --wav FILE        render the audio of a headless run to a WAV file
+    --terminal        draw in the terminal instead of opening a window
+    --glyphs SET      terminal glyphs: half (default) or braille
+    --key-hold MS     how long a terminal key counts as held after it was
+                      last seen, default 150
+    --frames N        number of ticks to run in headless mode without a
+                      movie: instructions, or 60 Hz frames with vip_timing
+                      or --vip
+    --seed N          seed for the random number generator
+    --tickrate N      instructions per 60 Hz frame, default 8; the faster
+                      and slower hotkeys (= and -) change it while the ROM
+                      runs, or the cycles per frame with vip_timing or --vip
+    --quirks LIST     comma separated list of quirks to enable: shift_vy,
+                      load_store_inc_i, jump_vx, vf_reset, clip_sprites,
+                      wait_key_press, vip_timing (run a 60 Hz frame of
+                      COSMAC VIP cycles per tick), display_wait (with
+                      vip_timing, DXYN waits for the next frame)";
+
+pub struct Args {
+    pub rom: String,
+    pub config: Option<String>,
+    pub database: Option<String>,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub headless: bool,
+    pub disassemble: bool,
+    pub diff: Option<Quirks>,
+    pub watch: bool,
+    pub keep_state: bool,
+    pub font: Option<Font>,
+    pub gdb: Option<u16>,
+    pub vip: Option<String>,
+    pub vip_monitor: Option<String>,
+    pub palette: Option<Palette>,
+    pub filter: Option<Filter>,
+    pub scale: u32,
+    pub integer_scale: bool,
+    pub fullscreen: bool,
+    pub show_fps: bool,
+    pub screenshot: Option<String>,
+    pub capture: Option<String>,
+    pub capture_scale: u32,
+    pub wav: Option<String>,
+    pub terminal: bool,
+    pub glyphs: Glyphs,
+    pub key_hold: u64,
+    pub frames: Option<u64>,
+    pub seed: Option<u64>,
+    pub tickrate: Option<u32>,
+    pub quirks: Option<Quirks>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            rom: "pong1player.ch8".to_string(),
+            config: None,
+            database: None,
+            record: None,
+            play: None,
+            headless: false,
+            disassemble: false,
+            diff: None,
+            watch: false,
+            keep_state: false,
+            font: None,
+            gdb: None,
+            vip: None,
+            vip_monitor: None,
+            palette: None,
+            filter: None,
+            scale: screen::DEFAULT_SCALE,
+            integer_scale: false,
+            fullscreen: false,
+            show_fps: false,
+            screenshot: None,
+            capture: None,
+            capture_scale: capture::DEFAULT_SCALE,
+            wav: None,
+            terminal: false,
+            glyphs: Glyphs::HalfBlock,
+            key_hold: 150,
+            frames: None,
+            seed: None,
+            tickrate: None,
+            quirks: None,
+        }
+    }
+}
+
+fn usage_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n\n{}", msg, USAGE))
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Args> {
+        let mut parsed = Args::default();
+        let mut rom = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| usage_error(format!("missing value for {}", name)))
+            };
+            match arg.as_str() {
+                "--config" => parsed.config = Some(value(&arg)?),
+                "--database" => parsed.database = Some(value(&arg)?),
+                "--record" => parsed.record = Some(value(&arg)?),
+                "--play" => parsed.play = Some(value(&arg)?),
+                "--headless" => parsed.headless = true,
+                "--disassemble" => parsed.disassemble = true,
+                "--diff" => {
+                    let quirks = value(&arg)?.parse::<Quirks>().map_err(usage_error)?;
+                    parsed.diff = Some(quirks);
+                }
+                "--watch" => parsed.watch = true,
+                "--keep-state" => parsed.keep_state = true,
+                "--font" => {
+                    let font =
+                        Font::resolve(&value(&arg)?).map_err(|e| usage_error(e.to_string()))?;
+                    parsed.font = Some(font);
+                }
+                "--gdb" => match parse_number(&arg, &value(&arg)?)? {
+                    port if port > u64::from(u16::MAX) => {
+                        return Err(usage_error(format!("invalid port {}", port)))
+                    }
+                    port => parsed.gdb = Some(port as u16),
+                },
+                "--vip" => parsed.vip = Some(value(&arg)?),
+                "--vip-monitor" => parsed.vip_monitor = Some(value(&arg)?),
+                "--palette" => parsed.palette = Some(value(&arg)?.parse().map_err(usage_error)?),
+                "--filter" => parsed.filter = Some(value(&arg)?.parse().map_err(usage_error)?),
+                "--scale" => match parse_number(&arg, &value(&arg)?)? {
+                    0 => return Err(usage_error("--scale must be at least 1".to_string())),
+                    scale => parsed.scale = scale as u32,
+                },
+                "--integer-scale" => parsed.integer_scale = true,
+                "--fullscreen" => parsed.fullscreen = true,
+                "--show-fps" => parsed.show_fps = true,
+                "--screenshot" => parsed.screenshot = Some(value(&arg)?),
+                "--capture" => parsed.capture = Some(value(&arg)?),
+                "--capture-scale" => match parse_number(&arg, &value(&arg)?)? {
+                    scale if scale >= 1 && scale <= u64::from(capture::MAX_SCALE) => {
+                        parsed.capture_scale = scale as u32
+                    }
+                    _ => {
+                        return Err(usage_error(format!(
+                            "--capture-scale must be between 1 and {}",
+                            capture::MAX_SCALE
+                        )))
+                    }
+                },
+                "--wav" => parsed.wav = Some(value(&arg)?),
+                "--terminal" => parsed.terminal = true,
+                "--glyphs" => parsed.glyphs = value(&arg)?.parse().map_err(usage_error)?,
+                "--key-hold" => parsed.key_hold = parse_number(&arg, &value(&arg)?)?,
+                "--frames" => parsed.frames = Some(parse_number(&arg, &value(&arg)?)?),
+                "--seed" => parsed.seed = Some(parse_number(&arg, &value(&arg)?)?),
+                "--tickrate" => match parse_number(&arg, &value(&arg)?)? {
+                    0 => return Err(usage_error("--tickrate must be at least 1".to_string())),
+                    tickrate => parsed.tickrate = Some(tickrate.min(u64::from(u32::MAX)) as u32),
+                },
+                "--quirks" => {
+                    let quirks = value(&arg)?.parse::<Quirks>().map_err(usage_error)?;
+                    parsed.quirks = Some(quirks);
+                }
+                "-h" | "--help" => return Err(usage_error("".to_string())),
+                _ if arg.starts_with("--") => {
+                    return Err(usage_error(format!("unknown option: {}", arg)))
+                }
+                _ if rom.is_none() => rom = Some(arg),
+                _ => return Err(usage_error(format!("unexpected argument: {}", arg))),
+            }
+        }
+
+        if parsed.record.is_some() && parsed.play.is_some() {
+            return Err(usage_error(
+                "--record and --play cannot be used together".to_string(),
+            ));
+        }
+        if parsed.headless && parsed.terminal {
+            return Err(usage_error(
+                "--headless and --terminal cannot be used together".to_string(),
+            ));
+        }
+        if parsed.watch && (parsed.record.is_some() || parsed.play.is_some()) {
+            return Err(usage_error(
+                "--watch cannot be used with --record or --play".to_string(),
+            ));
+        }
+        if parsed.diff.is_some() && parsed.play.is_none() && parsed.frames.is_none() {
+            return Err(usage_error("--diff needs --play or --frames".to_string()));
+        }
+        if parsed.vip.is_some() != parsed.vip_monitor.is_some() {
+            return Err(usage_error(
+                "--vip and --vip-monitor go together".to_string(),
+            ));
+        }
+        if parsed.gdb.is_some() && parsed.vip.is_some() {
+            return Err(usage_error("--gdb cannot be used with --vip".to_string()));
+        }
+        if parsed.keep_state && !parsed.watch {
+            return Err(usage_error("--keep-state needs --watch".to_string()));
+        }
+        if parsed.wav.is_some() && !parsed.headless {
+            return Err(usage_error("--wav needs --headless".to_string()));
+        }
+        if let Some(rom) = rom {
+            parsed.rom = rom;
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> io::Result<u64> {
+    value
+        .parse::<u64>()
+        .map_err(|e| usage_error(format!("invalid value for {}: {}", name, e)))
+}
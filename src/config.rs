@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Config file looked up in the working directory when `--config` is not
+/// given.
+pub const DEFAULT_PATH: &str = "chip8.ini";
+
+/// A `key = value` line together with where it came from, for error
+/// messages.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+/// `[name]` applies to every ROM, `[name:rom]` only to the ROM whose file
+/// name or SHA-1 is `rom`.
+#[derive(Clone, Debug)]
+struct Section {
+    name: String,
+    rom: Option<String>,
+    entries: Vec<Entry>,
+}
+
+/// `line` without its comment. A `#` or `;` starts a comment at the
+/// beginning of a line or after a section header, but within a value only
+/// with whitespace on both sides, so that `palette = #000000, #FFFFFF` keeps
+/// its colors and `5 = W, ;` its key.
+fn strip_comment(line: &str) -> &str {
+    let is_comment = |s: &str| s.starts_with('#') || s.starts_with(';');
+    let line = line.trim();
+    if is_comment(line) {
+        return "";
+    }
+    if line.starts_with('[') {
+        return match line.find(']') {
+            Some(end) if is_comment(line[end + 1..].trim_start()) => &line[..=end],
+            _ => line,
+        };
+    }
+    let bytes = line.as_bytes();
+    for pos in 1..bytes.len().saturating_sub(1) {
+        if (bytes[pos] == b'#' || bytes[pos] == b';')
+            && bytes[pos - 1].is_ascii_whitespace()
+            && bytes[pos + 1].is_ascii_whitespace()
+        {
+            return line[..pos].trim_end();
+        }
+    }
+    line
+}
+
+/// An INI style config file:
+///
+/// ```ini
+/// # global key bindings
+/// [keys]
+/// 5 = W, Up
+///
+/// # only for Pong
+/// [keys:pong1player.ch8]
+/// 1 = scancode:W
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    path: String,
+    sections: Vec<Section>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut text = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut text)?;
+        Config::parse(&path.as_ref().display().to_string(), &text)
+    }
+
+    pub fn parse(path: &str, text: &str) -> io::Result<Self> {
+        let mut config = Config {
+            path: path.to_string(),
+            sections: Vec::new(),
+        };
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') || line.len() < 3 {
+                    return Err(config.error_at(line_no, "malformed section header"));
+                }
+                let header = &line[1..line.len() - 1];
+                let mut parts = header.splitn(2, ':');
+                let name = parts.next().unwrap_or("").trim().to_lowercase();
+                let rom = parts.next().map(|rom| rom.trim().to_string());
+                config.sections.push(Section {
+                    name,
+                    rom,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(config.error_at(line_no, "expected `key = value`")),
+            };
+            let entry = Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+                line: line_no,
+            };
+            match config.sections.last_mut() {
+                Some(section) => section.entries.push(entry),
+                None => {
+                    return Err(config.error_at(line_no, "entry outside of a [section]"));
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Entries of every `[name]` section, followed by the ones of
+    /// `[name:rom]` sections matching `rom_name` or `rom_hash`, so that
+    /// applying them in order lets per-ROM settings win.
+    pub fn entries(&self, name: &str, rom_name: &str, rom_hash: &str) -> Vec<&Entry> {
+        let global = self
+            .sections
+            .iter()
+            .filter(|s| s.name == name && s.rom.is_none());
+        let per_rom = self.sections.iter().filter(|s| {
+            s.name == name
+                && s.rom
+                    .as_ref()
+                    .is_some_and(|rom| rom == rom_name || rom.eq_ignore_ascii_case(rom_hash))
+        });
+        global
+            .chain(per_rom)
+            .flat_map(|s| s.entries.iter())
+            .collect()
+    }
+
+    fn error_at(&self, line: usize, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", self.path, line, msg),
+        )
+    }
+
+    /// Error pointing at the line `entry` was read from.
+    pub fn error(&self, entry: &Entry, msg: &str) -> io::Error {
+        self.error_at(entry.line, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_hex_colors_in_values() {
+        let text = "\
+# colors
+[display] ; every ROM
+palette = #000000, #FFFFFF # black and white
+[display:pong1player.ch8]#dim
+palette=#102030,#405060
+";
+        let config = Config::parse("test.ini", text).unwrap();
+        let global = config.entries("display", "brix.ch8", "");
+        assert_eq!(global.len(), 1);
+        assert_eq!(global[0].key, "palette");
+        assert_eq!(global[0].value, "#000000, #FFFFFF");
+        let per_rom = config.entries("display", "pong1player.ch8", "");
+        assert_eq!(per_rom.len(), 2);
+        assert_eq!(per_rom[1].value, "#102030,#405060");
+        assert_eq!(per_rom[1].line, 5);
+    }
+
+    #[test]
+    fn strips_trailing_comments() {
+        let text = "\
+[keys]
+5 = W, ;
+6 = E ; the right key
+; 7 = R
+8 = T, #
+";
+        let config = Config::parse("test.ini", text).unwrap();
+        let values = config
+            .entries("keys", "", "")
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![("5", "W, ;"), ("6", "E"), ("8", "T, #")]);
+    }
+}
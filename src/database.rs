@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use interpreter::Quirks;
+use json::Json;
+use palette::Palette;
+
+/// Database file looked up in the working directory when `--database` is
+/// not given: a copy of `programs.json` from the CHIP-8 database project.
+pub const DEFAULT_PATH: &str = "chip-8-database.json";
+
+/// The platforms of the database whose quirks this interpreter can
+/// reproduce, by database id, with their display names.
+const PLATFORMS: [(&str, &str); 8] = [
+    ("originalChip8", "CHIP-8"),
+    ("hybridVIP", "CHIP-8 hybrid"),
+    ("modernChip8", "CHIP-8"),
+    ("chip48", "CHIP-48"),
+    ("superchip1", "SCHIP 1.0"),
+    ("superchip", "SCHIP 1.1"),
+    ("megachip8", "MEGA-CHIP"),
+    ("xochip", "XO-CHIP"),
+];
+
+/// Quirks of a platform before the per-ROM overrides.
+fn platform_quirks(platform: &str) -> Quirks {
+    let vip = Quirks {
+        shift_vy: true,
+        load_store_inc_i: true,
+        jump_vx: false,
+        vf_reset: true,
+        clip_sprites: true,
+        wait_key_press: false,
+        vip_timing: false,
+        display_wait: false,
+    };
+    let schip = Quirks {
+        shift_vy: false,
+        load_store_inc_i: false,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        wait_key_press: false,
+        vip_timing: false,
+        display_wait: false,
+    };
+    match platform {
+        "originalChip8" | "hybridVIP" => Quirks {
+            vip_timing: true,
+            display_wait: true,
+            ..vip
+        },
+        "modernChip8" => Quirks {
+            vf_reset: false,
+            ..vip
+        },
+        // CHIP-48 and SCHIP 1.0 add X to I on FX55/FX65, which is closer to
+        // incrementing it than to leaving it alone
+        "chip48" | "superchip1" => Quirks {
+            load_store_inc_i: true,
+            ..schip
+        },
+        "superchip" | "megachip8" => schip,
+        "xochip" => Quirks {
+            vf_reset: false,
+            clip_sprites: false,
+            ..vip
+        },
+        _ => Quirks::default(),
+    }
+}
+
+/// Applies the database quirk flags in `flags` on top of `quirks`.
+fn apply_flags(quirks: &mut Quirks, flags: &Json) {
+    let flag = |name: &str| flags.get(name).and_then(Json::as_bool);
+    if let Some(shift) = flag("shift") {
+        quirks.shift_vy = !shift;
+    }
+    if let Some(leave) = flag("memoryLeaveIUnchanged") {
+        quirks.load_store_inc_i = !leave;
+    }
+    if let Some(true) = flag("memoryIncrementByX") {
+        quirks.load_store_inc_i = true;
+    }
+    if let Some(wrap) = flag("wrap") {
+        quirks.clip_sprites = !wrap;
+    }
+    if let Some(jump) = flag("jump") {
+        quirks.jump_vx = jump;
+    }
+    if let Some(logic) = flag("logic") {
+        quirks.vf_reset = logic;
+    }
+    if let Some(vblank) = flag("vblank") {
+        quirks.display_wait = vblank;
+    }
+}
+
+/// What the database knows about a ROM.
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Database id of the platform the ROM was written for.
+    pub platform: String,
+    pub quirks: Quirks,
+    /// Recommended instructions per 60 Hz frame.
+    pub tickrate: Option<u32>,
+    /// What some CHIP-8 keys do in the game, e.g. `up` for key 5.
+    pub keys: Vec<(String, u8)>,
+    pub colors: Option<Palette>,
+}
+
+impl RomInfo {
+    pub fn platform_name(&self) -> &str {
+        PLATFORMS
+            .iter()
+            .find(|p| p.0 == self.platform)
+            .map_or(&self.platform, |p| p.1)
+    }
+
+    fn parse(program: &Json, rom: &Json) -> RomInfo {
+        let strings = |value: Option<&Json>| {
+            value
+                .and_then(Json::as_array)
+                .unwrap_or(&[])
+                .iter()
+                .filter_map(Json::as_str)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let platforms = strings(rom.get("platforms"));
+        // platforms are listed best first
+        let platform = platforms
+            .iter()
+            .find(|id| PLATFORMS.iter().any(|p| p.0 == id.as_str()))
+            .or_else(|| platforms.first())
+            .cloned()
+            .unwrap_or_default();
+
+        let mut quirks = platform_quirks(&platform);
+        if let Some(flags) = rom.get("quirkyPlatforms").and_then(|q| q.get(&platform)) {
+            apply_flags(&mut quirks, flags);
+        }
+
+        let keys = rom
+            .get("keys")
+            .and_then(Json::as_object)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|(name, key)| {
+                let key = key.as_f64()?;
+                if (0.0..16.0).contains(&key) {
+                    Some((name.clone(), key as u8))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let colors = strings(rom.get("colors").and_then(|c| c.get("pixels")));
+        let colors = if colors.is_empty() {
+            None
+        } else {
+            colors.join(",").parse::<Palette>().ok()
+        };
+
+        RomInfo {
+            title: program
+                .get("title")
+                .and_then(Json::as_str)
+                .unwrap_or("untitled")
+                .to_string(),
+            authors: strings(program.get("authors")),
+            platform,
+            quirks,
+            tickrate: rom
+                .get("tickrate")
+                .and_then(Json::as_f64)
+                .filter(|&t| t >= 1.0)
+                .map(|t| t as u32),
+            keys,
+            colors,
+        }
+    }
+}
+
+/// `Title by Author (Platform)`.
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.title)?;
+        if !self.authors.is_empty() {
+            write!(f, " by {}", self.authors.join(", "))?;
+        }
+        write!(f, " ({})", self.platform_name())
+    }
+}
+
+/// ROMs of the CHIP-8 database, by the SHA-1 of their contents.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut text = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut text)?;
+        Database::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.as_ref().display(), e),
+            )
+        })
+    }
+
+    /// Parses the `programs.json` format: an array of programs, each with
+    /// its ROM versions keyed by hash.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json = Json::parse(text)?;
+        let programs = json
+            .as_array()
+            .ok_or_else(|| "expected an array of programs".to_string())?;
+        let mut roms = HashMap::new();
+        for program in programs {
+            let versions = program.get("roms").and_then(Json::as_object).unwrap_or(&[]);
+            for (hash, rom) in versions {
+                roms.insert(hash.to_lowercase(), RomInfo::parse(program, rom));
+            }
+        }
+        Ok(Database { roms })
+    }
+
+    pub fn lookup(&self, rom_hash: &str) -> Option<&RomInfo> {
+        self.roms.get(&rom_hash.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two programs in the format of `programs.json`.
+    const PROGRAMS: &str = r##"[
+  {
+    "title": "Brix",
+    "authors": ["Andreas Gustafsson"],
+    "roms": {
+      "0C5BE7BA1DBBE46E4AD1B6D6B4E2D0B0C26C1F53": {
+        "platforms": ["originalChip8", "modernChip8"],
+        "quirkyPlatforms": {
+          "originalChip8": {"shift": true, "vblank": false}
+        },
+        "tickrate": 15,
+        "keys": {"left": 4, "right": 6, "bad": 16},
+        "colors": {"pixels": ["#000000", "#00FF00"]}
+      }
+    }
+  },
+  {
+    "title": "Sweet Copter",
+    "roms": {
+      "aaaa": {"platforms": ["superchip"], "tickrate": 0},
+      "bbbb": {"platforms": ["unknownChip", "xochip"], "colors": {"pixels": ["nope"]}},
+      "cccc": {"platforms": ["unknownChip"]}
+    }
+  }
+]"##;
+
+    #[test]
+    fn reads_programs() {
+        let database = Database::parse(PROGRAMS).unwrap();
+        let brix = database
+            .lookup("0c5be7ba1dbbe46e4ad1b6d6b4e2d0b0c26c1f53")
+            .unwrap();
+        assert_eq!(brix.to_string(), "Brix by Andreas Gustafsson (CHIP-8)");
+        assert_eq!(brix.platform, "originalChip8");
+        assert_eq!(brix.tickrate, Some(15));
+        assert_eq!(
+            brix.keys,
+            vec![("left".to_string(), 4), ("right".to_string(), 6)]
+        );
+        assert!(brix.colors.is_some());
+        // the flags say the shift is on VX and there is no display wait
+        assert_eq!(
+            brix.quirks,
+            Quirks {
+                shift_vy: false,
+                display_wait: false,
+                ..platform_quirks("originalChip8")
+            }
+        );
+        assert!(brix.quirks.vip_timing && brix.quirks.vf_reset);
+        assert!(database.lookup("0c5be7ba").is_none());
+    }
+
+    #[test]
+    fn falls_back_for_unknown_platforms_and_values() {
+        let database = Database::parse(PROGRAMS).unwrap();
+        let schip = database.lookup("AAAA").unwrap();
+        assert_eq!(schip.to_string(), "Sweet Copter (SCHIP 1.1)");
+        assert_eq!(schip.tickrate, None);
+        assert!(schip.quirks.jump_vx && !schip.quirks.shift_vy);
+        // the first platform this interpreter knows wins
+        let xochip = database.lookup("bbbb").unwrap();
+        assert_eq!(xochip.platform, "xochip");
+        assert!(xochip.colors.is_none());
+        let unknown = database.lookup("cccc").unwrap();
+        assert_eq!(unknown.platform_name(), "unknownChip");
+        assert_eq!(unknown.quirks, Quirks::default());
+    }
+
+    #[test]
+    fn rejects_other_documents() {
+        assert_eq!(
+            Database::parse("{}").err(),
+            Some("expected an array of programs".to_string())
+        );
+        assert!(Database::parse("[{]").unwrap_err().starts_with("line 1:"));
+        assert!(Database::parse("[]").unwrap().lookup("").is_none());
+    }
+}
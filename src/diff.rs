@@ -0,0 +1,329 @@
+use std::fmt;
+use std::io;
+
+use disassembler::Listing;
+use interpreter::{Backend, Fault, Interpreter, MemoryLayout, Options, Snapshot};
+
+/// Instructions shown before and after the one that diverged.
+const WINDOW: usize = 4;
+
+/// Differing memory bytes listed before the rest is only counted.
+const MAX_MEMORY_LINES: usize = 16;
+
+/// The first point where two runs of a ROM stopped agreeing.
+pub struct Divergence {
+    /// Ticks run before the states differed, 0 when they already differ
+    /// before the first instruction.
+    pub tick: usize,
+    /// Address and opcode of the instruction run during that tick.
+    pub pc: usize,
+    pub opcode: u16,
+    /// Name, value in run A and value in run B of every differing register.
+    pub registers: Vec<(String, String, String)>,
+    /// Address and bytes in run A and B, `None` past the end of memory.
+    pub memory: Vec<(usize, Option<u8>, Option<u8>)>,
+    pub vram: bool,
+    /// Faults of run A and B during that tick, when they differ.
+    pub faults: (Option<Fault>, Option<Fault>),
+    /// Disassembly of run A around `pc`.
+    pub window: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.tick == 0 {
+            writeln!(f, "the runs differ before the first instruction")?;
+        } else {
+            writeln!(
+                f,
+                "the runs diverge at tick {}, running {:04X} at ${:03X}",
+                self.tick, self.opcode, self.pc
+            )?;
+        }
+        if !self.registers.is_empty() {
+            writeln!(f, "\nregister  a     b")?;
+            for (name, a, b) in &self.registers {
+                writeln!(f, "{:<9} {:<5} {}", name, a, b)?;
+            }
+        }
+        if !self.memory.is_empty() {
+            writeln!(f, "\nmemory    a     b")?;
+            let byte = |b: Option<u8>| b.map_or("--".to_string(), |b| format!("{:02X}", b));
+            for &(address, a, b) in self.memory.iter().take(MAX_MEMORY_LINES) {
+                writeln!(f, "${:03X}     {:<5} {}", address, byte(a), byte(b))?;
+            }
+            if self.memory.len() > MAX_MEMORY_LINES {
+                writeln!(f, "and {} more bytes", self.memory.len() - MAX_MEMORY_LINES)?;
+            }
+        }
+        if self.vram {
+            writeln!(f, "\nthe display differs")?;
+        }
+        write_faults(f, self.faults)?;
+        write!(f, "\n{}", self.window)
+    }
+}
+
+/// The first 60 Hz frame after which two backends stopped agreeing on what
+/// the player sees and hears.
+pub struct FrameDivergence {
+    /// Frames run, including the one after which the runs differed.
+    pub frame: usize,
+    pub vram: bool,
+    /// Whether the beeper of run A and B sounds at the end of that frame.
+    pub beep: (bool, bool),
+    /// Faults of run A and B during that frame, when they differ.
+    pub faults: (Option<Fault>, Option<Fault>),
+}
+
+impl fmt::Display for FrameDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "the runs diverge in frame {}", self.frame)?;
+        if self.vram {
+            writeln!(f, "\nthe display differs")?;
+        }
+        if self.beep.0 != self.beep.1 {
+            writeln!(f)?;
+            for &(name, beep) in [("a", self.beep.0), ("b", self.beep.1)].iter() {
+                let sound = if beep { "beeps" } else { "is silent" };
+                writeln!(f, "run {} {}", name, sound)?;
+            }
+        }
+        write_faults(f, self.faults)
+    }
+}
+
+fn write_faults(f: &mut fmt::Formatter, faults: (Option<Fault>, Option<Fault>)) -> fmt::Result {
+    if faults.0 != faults.1 {
+        writeln!(f)?;
+        for (name, fault) in [("a", faults.0), ("b", faults.1)].iter() {
+            match *fault {
+                Some(fault) => writeln!(f, "run {} stops: {}", name, fault)?,
+                None => writeln!(f, "run {} goes on", name)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compare_registers(a: &Snapshot, b: &Snapshot) -> Vec<(String, String, String)> {
+    let mut registers = Vec::new();
+    {
+        let mut check = |name: String, a: usize, b: usize| {
+            if a != b {
+                registers.push((name, format!("{:X}", a), format!("{:X}", b)));
+            }
+        };
+        for idx in 0..16 {
+            check(format!("V{:X}", idx), a.v[idx] as usize, b.v[idx] as usize);
+        }
+        check("I".to_string(), a.i, b.i);
+        check("PC".to_string(), a.pc, b.pc);
+        check("SP".to_string(), a.sp, b.sp);
+        check("DT".to_string(), a.dt as usize, b.dt as usize);
+        check("ST".to_string(), a.st as usize, b.st as usize);
+        for idx in 0..16 {
+            check(format!("stack[{}]", idx), a.stack[idx], b.stack[idx]);
+        }
+    }
+    registers
+}
+
+fn compare_memory(a: &[u8], b: &[u8]) -> Vec<(usize, Option<u8>, Option<u8>)> {
+    (0..a.len().max(b.len()))
+        .map(|address| (address, a.get(address).cloned(), b.get(address).cloned()))
+        .filter(|&(_, a, b)| a != b)
+        .collect()
+}
+
+/// Disassembly of the instructions around `pc`, with an arrow at `pc`.
+fn window(memory: &[u8], layout: MemoryLayout, pc: usize) -> String {
+    let start = pc.saturating_sub(WINDOW * 2);
+    let end = (pc + (WINDOW + 1) * 2).min(memory.len());
+    if start >= end {
+        return String::new();
+    }
+    let layout = MemoryLayout {
+        load_address: start,
+        ..layout
+    };
+    let listing = Listing::new(&memory[start..end], layout).to_string();
+    listing
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let marker = if start + idx * 2 == pc { "-> " } else { "   " };
+            format!("{}{}\n", marker, line)
+        })
+        .collect()
+}
+
+/// Runs `program` with the options of `a` and `b` side by side, feeding
+/// both the same `frames` of keys, and compares their state after every
+/// tick. Without `vip_timing` a tick is exactly one instruction.
+///
+/// Returns the first divergence, or `None` when the runs agree until the
+/// frames run out. Runs that fault the same way agree, but as they cannot
+/// go on, that fault is returned. Fails when `program` does not fit the
+/// memory layout of either run.
+pub fn compare(
+    program: &[u8],
+    a: Options,
+    b: Options,
+    frames: &[[bool; 16]],
+) -> io::Result<Result<Option<Divergence>, Fault>> {
+    let mut run_a = Interpreter::with_options(a);
+    let mut run_b = Interpreter::with_options(b);
+    run_a.load(program)?;
+    run_b.load(program)?;
+
+    let mut before = run_a.snapshot();
+    for tick in 0..=frames.len() {
+        let mut faults = (None, None);
+        if tick > 0 {
+            faults = (
+                run_a.tick(frames[tick - 1]).err(),
+                run_b.tick(frames[tick - 1]).err(),
+            );
+        }
+        let (state_a, state_b) = (run_a.snapshot(), run_b.snapshot());
+        if state_a != state_b || faults.0 != faults.1 {
+            let pc = before.pc;
+            let opcode = match (before.memory.get(pc), before.memory.get(pc + 1)) {
+                (Some(&high), Some(&low)) => (u16::from(high) << 8) | u16::from(low),
+                _ => 0,
+            };
+            return Ok(Ok(Some(Divergence {
+                tick,
+                pc,
+                opcode,
+                registers: compare_registers(&state_a, &state_b),
+                memory: compare_memory(&state_a.memory, &state_b.memory),
+                vram: state_a.vram != state_b.vram,
+                faults,
+                window: window(&before.memory, a.layout, pc),
+            })));
+        }
+        if let Some(fault) = faults.0 {
+            return Ok(Err(fault));
+        }
+        before = state_a;
+    }
+    Ok(Ok(None))
+}
+
+/// Ticks `backend` until the end of a 60 Hz frame, returning whether it
+/// beeps then.
+fn run_frame(backend: &mut dyn Backend, keyboard: [bool; 16]) -> Result<bool, Fault> {
+    loop {
+        let state = backend.tick(keyboard)?;
+        if state.end_of_frame {
+            return Ok(state.beep);
+        }
+    }
+}
+
+/// Runs two backends with a program already loaded side by side, holding
+/// the keys of `frames` for a 60 Hz frame each, and compares the display
+/// and the beeper after every frame. Unlike `compare` this works across
+/// backends whose machine states have nothing in common, such as the
+/// interpreter and the VIP.
+///
+/// Returns the first divergence, or `None` when the runs agree until the
+/// frames run out. Runs that fault in the same frame agree, and the fault
+/// of run A is returned.
+pub fn compare_frames(
+    a: &mut dyn Backend,
+    b: &mut dyn Backend,
+    frames: &[[bool; 16]],
+) -> Result<Option<FrameDivergence>, Fault> {
+    for (frame, &keyboard) in frames.iter().enumerate() {
+        let (beep_a, beep_b) = (run_frame(a, keyboard), run_frame(b, keyboard));
+        let faults = (beep_a.err(), beep_b.err());
+        let beep = (beep_a.unwrap_or(false), beep_b.unwrap_or(false));
+        let vram = a.vram() != b.vram();
+        if vram || beep.0 != beep.1 || faults.0 != faults.1 {
+            return Ok(Some(FrameDivergence {
+                frame: frame + 1,
+                vram,
+                beep,
+                faults,
+            }));
+        }
+        if let Some(fault) = faults.0 {
+            return Err(fault);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interpreter::Quirks;
+
+    fn options(shift_vy: bool) -> Options {
+        Options {
+            quirks: Quirks {
+                shift_vy,
+                ..Quirks::default()
+            },
+            seed: 0,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn finds_the_shift_that_diverges() {
+        // V0 = 1, V1 = 4, V0 >>= 1 or V0 = V1 >> 1
+        let program = [0x60, 0x01, 0x61, 0x04, 0x80, 0x16, 0x12, 0x06];
+        let frames = [[false; 16]; 8];
+        let divergence = compare(&program, options(false), options(true), &frames)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.tick, 3);
+        assert_eq!((divergence.pc, divergence.opcode), (0x204, 0x8016));
+        assert_eq!(
+            divergence.registers,
+            vec![
+                ("V0".to_string(), "0".to_string(), "2".to_string()),
+                ("VF".to_string(), "1".to_string(), "0".to_string()),
+            ]
+        );
+        assert!(divergence.memory.is_empty() && !divergence.vram);
+        assert!(divergence.window.contains("-> "));
+
+        assert!(compare(&program, options(true), options(true), &frames)
+            .unwrap()
+            .unwrap()
+            .is_none());
+        assert!(compare(&[0; 0xE01], options(true), options(true), &frames).is_err());
+    }
+
+    #[test]
+    fn compares_backends_per_frame() {
+        // draws the digit in V0 after the shift
+        let program = [
+            0x60, 0x01, 0x61, 0x04, 0x80, 0x16, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x0A,
+        ];
+        let frames = [[false; 16]; 2];
+        let run = |shift_vy: bool| {
+            let mut interpreter = Interpreter::with_options(options(shift_vy));
+            interpreter.load(&program).unwrap();
+            interpreter
+        };
+
+        let divergence = compare_frames(&mut run(false), &mut run(true), &frames)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.frame, 1);
+        assert!(divergence.vram);
+        assert_eq!(divergence.beep, (false, false));
+        assert_eq!(divergence.faults, (None, None));
+
+        assert!(compare_frames(&mut run(true), &mut run(true), &frames)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 
+use interpreter::MemoryLayout;
+
+/// One instruction in assembly, or `DATA` for words that are none.
 pub struct Instruction(pub u16);
 
 impl fmt::Display for Instruction {
@@ -188,10 +191,9 @@ impl fmt::Display for Instruction {
                         (self.0 & 0x00F0) >> 4
                     );
                 }
-                _ => panic!(format!(
-                    "unsupported instruction {:04X} within nimble: {:X}",
-                    self.0, nimble
-                )),
+                _ => {
+                    let _res = writeln!(f, "{:04X}          DATA", self.0);
+                }
             },
             // Sets the I register to value NNN.
             0xA => {
@@ -211,12 +213,11 @@ impl fmt::Display for Instruction {
                     self.0 & 0x0FFF,
                 );
             }
-            // Jump to address NNN + V0
+            // sets VX to a random number ANDed with NN
             0xC => {
                 let _res = writeln!(
                     f,
-                    "{:04X}          
-                    RAND V{:X}, #${:02X}",
+                    "{:04X}          RAND V{:X}, #${:02X}",
                     self.0,
                     (self.0 & 0x0F00) >> 8,
                     self.0 & 0x00FF
@@ -252,10 +253,9 @@ impl fmt::Display for Instruction {
                         (self.0 & 0x0F00) >> 8,
                     );
                 }
-                _ => panic!(format!(
-                    "unsupported instruction {:04X} within nimble: {:X}",
-                    self.0, nimble
-                )),
+                _ => {
+                    let _res = writeln!(f, "{:04X}          DATA", self.0);
+                }
             },
             // sets VX to the value of the delay timer
             0xF => match self.0 & 0xFF {
@@ -313,6 +313,15 @@ impl fmt::Display for Instruction {
                         (self.0 & 0x0F00) >> 8,
                     );
                 }
+                // sets I to the location of the big sprite for the character in VX
+                0x30 => {
+                    let _res = writeln!(
+                        f,
+                        "{:04X}          BIGSPRITECHAR V{:X}",
+                        self.0,
+                        (self.0 & 0x0F00) >> 8,
+                    );
+                }
                 // stores the BCD representation of VX with the most significant
                 // digit at adress in I, the other digits at I + 1, I + 2
                 0x33 => {
@@ -331,13 +340,72 @@ impl fmt::Display for Instruction {
                 0x65 => {
                     let _res = writeln!(f, "{:04X}          MOVM V0-VX, (I)", self.0);
                 }
-                _ => panic!(format!(
-                    "unsupported instruction {:04X} within nimble: {:X}",
-                    self.0, nimble
-                )),
+                _ => {
+                    let _res = writeln!(f, "{:04X}          DATA", self.0);
+                }
             },
-            _ => panic!(format!("unsupported instruction with nimble: {:X}", nimble)),
+            _ => {
+                let _res = writeln!(f, "{:04X}          DATA", self.0);
+            }
         };
         Ok(())
     }
 }
+
+/// Whether `Instruction` can show `instr`, rather than it being data.
+fn is_instruction(instr: u16) -> bool {
+    match (instr >> 12, instr & 0xFF) {
+        (0x5, _) | (0x9, _) => instr & 0xF == 0,
+        (0x8, _) => matches!(instr & 0xF, 0..=7 | 0xE),
+        (0xE, 0x9E) | (0xE, 0xA1) => true,
+        (0xE, _) => false,
+        (0xF, low) => [0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x30, 0x33, 0x55, 0x65].contains(&low),
+        _ => true,
+    }
+}
+
+/// A program listed with the addresses it is loaded at, one instruction
+/// per line. Words that are no instruction are shown as data.
+pub struct Listing<'a> {
+    program: &'a [u8],
+    layout: MemoryLayout,
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(program: &'a [u8], layout: MemoryLayout) -> Self {
+        Listing { program, layout }
+    }
+}
+
+impl<'a> fmt::Display for Listing<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, word) in self.program.chunks(2).enumerate() {
+            let address = self.layout.load_address + idx * 2;
+            let instr = (u16::from(word[0]) << 8) | u16::from(*word.get(1).unwrap_or(&0));
+            write!(f, "{:03X}  ", address)?;
+            if instr & 0xF0FF == 0xF029 {
+                // FX29 points I into the font, wherever the layout puts it
+                writeln!(
+                    f,
+                    "{:04X}          SPRITECHAR V{:X} (font at ${:03X})",
+                    instr,
+                    (instr & 0x0F00) >> 8,
+                    self.layout.font_address
+                )?;
+            } else if instr & 0xF0FF == 0xF030 {
+                writeln!(
+                    f,
+                    "{:04X}          BIGSPRITECHAR V{:X} (font at ${:03X})",
+                    instr,
+                    (instr & 0x0F00) >> 8,
+                    self.layout.big_font_address
+                )?;
+            } else if is_instruction(instr) {
+                write!(f, "{}", Instruction(instr))?;
+            } else {
+                writeln!(f, "{:04X}          DATA", instr)?;
+            }
+        }
+        Ok(())
+    }
+}
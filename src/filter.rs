@@ -0,0 +1,375 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use config::Config;
+use frontend::Framebuffer;
+use palette::{Palette, Rgb};
+use CHIP8_HEIGHT;
+use CHIP8_WIDTH;
+
+/// Brightness of a fully lit pixel.
+pub const FULL: u8 = 255;
+
+/// Fading pixels darker than this are switched off, so that the phosphor
+/// filter settles instead of redrawing nearly black pixels forever.
+const MIN_INTENSITY: u8 = 8;
+
+/// Default number of framebuffers the deflicker filter ORs together.
+const DEFAULT_DEFLICKER_FRAMES: usize = 2;
+
+/// Default share of its brightness a phosphor pixel keeps per emulated
+/// frame.
+const DEFAULT_DECAY: f32 = 0.6;
+
+/// Filters reducing the flicker of sprites erased and redrawn with XOR.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Filter {
+    #[default]
+    None,
+    /// Shows every pixel lit in any of the last N framebuffers.
+    Deflicker(usize),
+    /// Pixels that go out fade, keeping the given share of their
+    /// brightness every emulated 60 Hz frame.
+    Phosphor(f32),
+}
+
+impl Filter {
+    /// The `filter` entry of the `[display]` config sections, per-ROM
+    /// sections winning over the global one.
+    pub fn from_config(
+        config: &Config,
+        rom_name: &str,
+        rom_hash: &str,
+    ) -> io::Result<Option<Filter>> {
+        let mut filter = None;
+        for entry in config.entries("display", rom_name, rom_hash) {
+            if entry.key == "filter" {
+                filter = Some(
+                    entry
+                        .value
+                        .parse()
+                        .map_err(|e: String| config.error(entry, &e))?,
+                );
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Parses `none`, `deflicker[:FRAMES]` or `phosphor[:DECAY]`.
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let arg = parts.next();
+        match (name, arg) {
+            ("none", None) => Ok(Filter::None),
+            ("deflicker", None) => Ok(Filter::Deflicker(DEFAULT_DEFLICKER_FRAMES)),
+            ("deflicker", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n >= 1 => Ok(Filter::Deflicker(n)),
+                _ => Err(format!("invalid number of deflicker frames `{}`", n)),
+            },
+            ("phosphor", None) => Ok(Filter::Phosphor(DEFAULT_DECAY)),
+            ("phosphor", Some(decay)) => match decay.parse::<f32>() {
+                Ok(decay) if (0.0..1.0).contains(&decay) => Ok(Filter::Phosphor(decay)),
+                _ => Err(format!(
+                    "invalid phosphor decay `{}` (expected 0 <= decay < 1)",
+                    decay
+                )),
+            },
+            _ => Err(format!(
+                "unknown filter `{}` (expected none, deflicker[:FRAMES] or phosphor[:DECAY])",
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::None => write!(f, "none"),
+            Filter::Deflicker(n) => write!(f, "deflicker:{}", n),
+            Filter::Phosphor(decay) => write!(f, "phosphor:{}", decay),
+        }
+    }
+}
+
+/// A filtered framebuffer: the palette entry of every pixel and how
+/// brightly it is lit, from 0 (background) to `FULL`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub pixels: Framebuffer,
+    pub intensity: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame {
+            pixels: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+            intensity: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+        }
+    }
+}
+
+impl Frame {
+    /// The unfiltered framebuffer, every set pixel fully lit.
+    pub fn new(framebuffer: &Framebuffer) -> Self {
+        let mut frame = Frame {
+            pixels: *framebuffer,
+            ..Frame::default()
+        };
+        for (row, pixels) in frame.intensity.iter_mut().zip(framebuffer.iter()) {
+            for (level, &p) in row.iter_mut().zip(pixels.iter()) {
+                *level = if p != 0 { FULL } else { 0 };
+            }
+        }
+        frame
+    }
+
+    /// Color of the pixel at (`x`, `y`), blended towards the background
+    /// as its intensity drops.
+    pub fn color(&self, palette: &Palette, x: usize, y: usize) -> Rgb {
+        let pixel = self.pixels[y][x];
+        let intensity = u32::from(self.intensity[y][x]);
+        if pixel == 0 || intensity == 0 {
+            return palette.color(0);
+        }
+        let (fr, fg, fb) = palette.color(pixel);
+        let (br, bg, bb) = palette.color(0);
+        let blend = |f: u8, b: u8| {
+            ((u32::from(f) * intensity + u32::from(b) * (u32::from(FULL) - intensity))
+                / u32::from(FULL)) as u8
+        };
+        (blend(fr, br), blend(fg, bg), blend(fb, bb))
+    }
+
+    /// Rows of colors, for frontends that draw RGB.
+    pub fn rows(&self, palette: &Palette) -> Vec<Vec<Rgb>> {
+        (0..CHIP8_HEIGHT)
+            .map(|y| {
+                (0..CHIP8_WIDTH)
+                    .map(|x| self.color(palette, x, y))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Applies a `Filter` to the framebuffers the interpreter produces.
+///
+/// Every changed framebuffer is `push`ed, `end_frame` is called at the end
+/// of every emulated frame, and `apply` whenever a frame is presented. The
+/// filter keeps asking for presents while it is `fading`, so that
+/// afterglows go out even when the game stands still.
+pub struct FrameFilter {
+    filter: Filter,
+    history: VecDeque<Framebuffer>,
+    /// A framebuffer came in during the current emulated frame.
+    pushed: bool,
+    frame: Frame,
+}
+
+impl FrameFilter {
+    pub fn new(filter: Filter) -> Self {
+        let mut history = VecDeque::new();
+        history.push_back([[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT]);
+        FrameFilter {
+            filter,
+            history,
+            pushed: false,
+            frame: Frame::default(),
+        }
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    fn capacity(&self) -> usize {
+        match self.filter {
+            Filter::Deflicker(n) => n,
+            _ => 1,
+        }
+    }
+
+    pub fn push(&mut self, framebuffer: &Framebuffer) {
+        self.history.push_back(*framebuffer);
+        while self.history.len() > self.capacity() {
+            self.history.pop_front();
+        }
+        self.pushed = true;
+    }
+
+    /// Whether presenting again would change the frame even without a
+    /// new framebuffer.
+    pub fn fading(&self) -> bool {
+        match self.filter {
+            Filter::None => false,
+            Filter::Deflicker(_) => self.history.len() > 1,
+            Filter::Phosphor(_) => {
+                let latest = self.latest();
+                self.frame
+                    .intensity
+                    .iter()
+                    .zip(latest.iter())
+                    .any(|(levels, pixels)| {
+                        levels
+                            .iter()
+                            .zip(pixels.iter())
+                            .any(|(&level, &p)| p == 0 && level > 0)
+                    })
+            }
+        }
+    }
+
+    fn latest(&self) -> &Framebuffer {
+        self.history.back().expect("filter history is never empty")
+    }
+
+    /// The frame to present now.
+    pub fn apply(&mut self) -> &Frame {
+        match self.filter {
+            Filter::None => self.frame = Frame::new(self.latest()),
+            Filter::Deflicker(_) => {
+                // newest first, so that a pixel shows its current value when
+                // it is set now
+                let mut frame = Frame::default();
+                for framebuffer in self.history.iter().rev() {
+                    for (y, row) in framebuffer.iter().enumerate() {
+                        for (x, &p) in row.iter().enumerate() {
+                            if p != 0 && frame.pixels[y][x] == 0 {
+                                frame.pixels[y][x] = p;
+                                frame.intensity[y][x] = FULL;
+                            }
+                        }
+                    }
+                }
+                self.frame = frame;
+            }
+            // pixels that went out only fade in `end_frame`, so that the
+            // afterglow lasts as long however often the display presents
+            Filter::Phosphor(_) => self.light(),
+        }
+        &self.frame
+    }
+
+    /// Lights the pixels set in the latest framebuffer fully.
+    fn light(&mut self) {
+        let latest = *self.latest();
+        for (y, row) in latest.iter().enumerate() {
+            for (x, &p) in row.iter().enumerate() {
+                if p != 0 {
+                    self.frame.pixels[y][x] = p;
+                    self.frame.intensity[y][x] = FULL;
+                }
+            }
+        }
+    }
+
+    /// Lets an emulated frame go by, fading the phosphor pixels that are
+    /// out, or dropping the oldest deflicker framebuffer when no new one
+    /// came in, so that erased pixels do not linger once the game stands
+    /// still.
+    pub fn end_frame(&mut self) {
+        let pushed = self.pushed;
+        self.pushed = false;
+        let decay = match self.filter {
+            Filter::Phosphor(decay) => decay,
+            Filter::Deflicker(_) => {
+                if !pushed && self.history.len() > 1 {
+                    self.history.pop_front();
+                }
+                return;
+            }
+            Filter::None => return,
+        };
+        self.light();
+        let latest = *self.latest();
+        for (y, row) in latest.iter().enumerate() {
+            for (x, &p) in row.iter().enumerate() {
+                let level = &mut self.frame.intensity[y][x];
+                if p == 0 {
+                    *level = (f32::from(*level) * decay) as u8;
+                    if *level < MIN_INTENSITY {
+                        *level = 0;
+                        self.frame.pixels[y][x] = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The frame last returned by `apply`.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framebuffer(lit: &[(usize, usize)]) -> Framebuffer {
+        let mut framebuffer = [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT];
+        for &(x, y) in lit {
+            framebuffer[y][x] = 1;
+        }
+        framebuffer
+    }
+
+    #[test]
+    fn deflicker_ors_the_last_framebuffers() {
+        let mut filter = FrameFilter::new(Filter::Deflicker(2));
+        filter.push(&framebuffer(&[(0, 0), (5, 5)]));
+        filter.push(&framebuffer(&[(1, 0), (5, 5)]));
+        filter.push(&framebuffer(&[(2, 0)]));
+        let frame = *filter.apply();
+        assert_eq!(frame.pixels, framebuffer(&[(1, 0), (2, 0), (5, 5)]));
+        assert_eq!(frame.intensity[5][5], FULL);
+        assert!(filter.fading());
+
+        // presenting again does not age the framebuffers, frames do
+        for _ in 0..10 {
+            assert_eq!(filter.apply().pixels, frame.pixels);
+        }
+        // a frame that pushed keeps them all
+        filter.end_frame();
+        assert_eq!(filter.apply().pixels, frame.pixels);
+        // without new framebuffers the older one ages out
+        filter.end_frame();
+        assert_eq!(filter.apply().pixels, framebuffer(&[(2, 0)]));
+        assert!(!filter.fading());
+        filter.end_frame();
+        assert_eq!(filter.apply().pixels, framebuffer(&[(2, 0)]));
+    }
+
+    #[test]
+    fn phosphor_fades_to_zero_per_frame() {
+        let mut filter = FrameFilter::new(Filter::Phosphor(0.5));
+        filter.push(&framebuffer(&[(3, 4)]));
+        assert_eq!(filter.apply().intensity[4][3], FULL);
+        filter.push(&framebuffer(&[]));
+
+        // presenting does not make pixels fade, frames do
+        for _ in 0..10 {
+            assert_eq!(filter.apply().intensity[4][3], FULL);
+        }
+        let mut levels = Vec::new();
+        while filter.fading() {
+            filter.end_frame();
+            levels.push(filter.apply().intensity[4][3]);
+        }
+        assert_eq!(levels, vec![127, 63, 31, 15, 0]);
+        assert_eq!(filter.frame().pixels[4][3], 0);
+
+        // lit pixels stay at full brightness
+        filter.push(&framebuffer(&[(3, 4)]));
+        filter.end_frame();
+        assert_eq!(filter.frame().intensity[4][3], FULL);
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use config::Config;
+use interpreter::FONTS;
+
+pub type SmallFont = [[u8; 5]; 16];
+pub type BigFont = [[u8; 10]; 16];
+
+/// The font of the COSMAC VIP interpreter, which shares rows between digits
+/// to fit in its ROM.
+const VIP: SmallFont = [
+    [0xF0, 0x90, 0x90, 0x90, 0xF0],
+    [0x60, 0x20, 0x20, 0x20, 0x70],
+    [0xF0, 0x10, 0xF0, 0x80, 0xF0],
+    [0xF0, 0x10, 0xF0, 0x10, 0xF0],
+    [0xA0, 0xA0, 0xF0, 0x20, 0x20],
+    [0xF0, 0x80, 0xF0, 0x10, 0xF0],
+    [0xF0, 0x80, 0xF0, 0x90, 0xF0],
+    [0xF0, 0x10, 0x10, 0x10, 0x10],
+    [0xF0, 0x90, 0xF0, 0x90, 0xF0],
+    [0xF0, 0x90, 0xF0, 0x10, 0xF0],
+    [0xF0, 0x90, 0xF0, 0x90, 0x90],
+    [0xF0, 0x50, 0x70, 0x50, 0xF0],
+    [0xF0, 0x80, 0x80, 0x80, 0xF0],
+    [0xF0, 0x50, 0x50, 0x50, 0xF0],
+    [0xF0, 0x80, 0xF0, 0x80, 0xF0],
+    [0xF0, 0x80, 0xF0, 0x80, 0x80],
+];
+
+/// The 3 pixel wide font of the DREAM 6800.
+const DREAM_6800: SmallFont = [
+    [0xE0, 0xA0, 0xA0, 0xA0, 0xE0],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0xE0, 0x20, 0xE0, 0x80, 0xE0],
+    [0xE0, 0x20, 0xE0, 0x20, 0xE0],
+    [0x80, 0xA0, 0xA0, 0xE0, 0x20],
+    [0xE0, 0x80, 0xE0, 0x20, 0xE0],
+    [0xE0, 0x80, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0x20, 0x20, 0x20, 0x20],
+    [0xE0, 0xA0, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0xA0, 0xE0, 0x20, 0xE0],
+    [0xE0, 0xA0, 0xE0, 0xA0, 0xA0],
+    [0xC0, 0xA0, 0xE0, 0xA0, 0xC0],
+    [0xE0, 0x80, 0x80, 0x80, 0xE0],
+    [0xC0, 0xA0, 0xA0, 0xA0, 0xC0],
+    [0xE0, 0x80, 0xE0, 0x80, 0xE0],
+    [0xE0, 0x80, 0xC0, 0x80, 0x80],
+];
+
+/// The 3 pixel wide font of the ETI-660.
+const ETI_660: SmallFont = [
+    [0xE0, 0xA0, 0xA0, 0xA0, 0xE0],
+    [0x20, 0x20, 0x20, 0x20, 0x20],
+    [0xE0, 0x20, 0xE0, 0x80, 0xE0],
+    [0xE0, 0x20, 0xE0, 0x20, 0xE0],
+    [0xA0, 0xA0, 0xE0, 0x20, 0x20],
+    [0xE0, 0x80, 0xE0, 0x20, 0xE0],
+    [0xE0, 0x80, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0x20, 0x20, 0x20, 0x20],
+    [0xE0, 0xA0, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0xA0, 0xE0, 0x20, 0xE0],
+    [0xE0, 0xA0, 0xE0, 0xA0, 0xA0],
+    [0x80, 0x80, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0x80, 0x80, 0x80, 0xE0],
+    [0x20, 0x20, 0xE0, 0xA0, 0xE0],
+    [0xE0, 0x80, 0xE0, 0x80, 0xE0],
+    [0xE0, 0x80, 0xC0, 0x80, 0x80],
+];
+
+/// The rounded font of the FISH 'N' CHIPS emulator.
+const FISH_N_CHIPS: SmallFont = [
+    [0x60, 0xA0, 0xA0, 0xA0, 0xC0],
+    [0x40, 0xC0, 0x40, 0x40, 0xE0],
+    [0xC0, 0x20, 0x40, 0x80, 0xE0],
+    [0xC0, 0x20, 0x40, 0x20, 0xC0],
+    [0x20, 0xA0, 0xE0, 0x20, 0x20],
+    [0xE0, 0x80, 0xC0, 0x20, 0xC0],
+    [0x40, 0x80, 0xC0, 0xA0, 0x40],
+    [0xE0, 0x20, 0x60, 0x40, 0x40],
+    [0x40, 0xA0, 0x40, 0xA0, 0x40],
+    [0x40, 0xA0, 0x60, 0x20, 0x40],
+    [0x40, 0xA0, 0xE0, 0xA0, 0xA0],
+    [0xC0, 0xA0, 0xC0, 0xA0, 0xC0],
+    [0x60, 0x80, 0x80, 0x80, 0x60],
+    [0xC0, 0xA0, 0xA0, 0xA0, 0xC0],
+    [0xE0, 0x80, 0xC0, 0x80, 0xE0],
+    [0xE0, 0x80, 0xC0, 0x80, 0x80],
+];
+
+/// The SCHIP 1.1 8x10 digits, followed by the letters XO-CHIP added.
+const SCHIP_BIG: BigFont = [
+    [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C],
+    [0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C],
+    [0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF],
+    [0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C],
+    [0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06],
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C],
+    [0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C],
+    [0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60],
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C],
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C],
+    [0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3],
+    [0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC],
+    [0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C],
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC],
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0],
+];
+
+const BUILTIN: [(&str, SmallFont); 5] = [
+    ("default", FONTS),
+    ("vip", VIP),
+    ("dream6800", DREAM_6800),
+    ("eti660", ETI_660),
+    ("fish", FISH_N_CHIPS),
+];
+
+/// Bytes of a small font, followed in font files by the big font.
+pub const SMALL_SIZE: usize = 16 * 5;
+pub const BIG_SIZE: usize = 16 * 10;
+
+/// The hex digit sprites the interpreter puts in memory, for FX29 (small)
+/// and FX30 (big).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Font {
+    /// Name of the built-in small font, `None` for fonts from a file.
+    pub name: Option<&'static str>,
+    pub small: SmallFont,
+    pub big: BigFont,
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font {
+            name: Some("default"),
+            small: FONTS,
+            big: SCHIP_BIG,
+        }
+    }
+}
+
+impl Font {
+    /// Reads a font file: 80 bytes of small font, optionally followed by
+    /// 160 bytes of big font.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Font> {
+        let mut data = Vec::new();
+        File::open(path.as_ref())?.read_to_end(&mut data)?;
+        Font::from_bytes(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.as_ref().display(), e),
+            )
+        })
+    }
+
+    /// A built-in font by name, or else a font file.
+    pub fn resolve(spec: &str) -> io::Result<Font> {
+        match spec.parse() {
+            Ok(font) => Ok(font),
+            Err(_) if Path::new(spec.trim()).is_file() => Font::load(spec.trim()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+    }
+
+    /// The `font` setting of the `[memory]` config sections, per-ROM
+    /// sections winning over the global one.
+    pub fn from_config(
+        config: &Config,
+        rom_name: &str,
+        rom_hash: &str,
+    ) -> io::Result<Option<Font>> {
+        let mut font = None;
+        for entry in config.entries("memory", rom_name, rom_hash) {
+            if entry.key == "font" {
+                font = Some(
+                    Font::resolve(&entry.value).map_err(|e| config.error(entry, &e.to_string()))?,
+                );
+            }
+        }
+        Ok(font)
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Font, String> {
+        if data.len() != SMALL_SIZE && data.len() != SMALL_SIZE + BIG_SIZE {
+            return Err(format!(
+                "a font has {} or {} bytes, not {}",
+                SMALL_SIZE,
+                SMALL_SIZE + BIG_SIZE,
+                data.len()
+            ));
+        }
+        let mut font = Font {
+            name: None,
+            ..Font::default()
+        };
+        for (digit, bytes) in font.small.iter_mut().zip(data.chunks(5)) {
+            digit.copy_from_slice(bytes);
+        }
+        for (digit, bytes) in font.big.iter_mut().zip(data[SMALL_SIZE..].chunks(10)) {
+            digit.copy_from_slice(bytes);
+        }
+        Ok(font)
+    }
+
+    /// Writes the small font at `small` and the big font at `big` in
+    /// `memory`.
+    pub fn write(&self, memory: &mut [u8], small: usize, big: usize) {
+        for (idx, &b) in self.small.iter().flat_map(|d| d.iter()).enumerate() {
+            memory[small + idx] = b;
+        }
+        for (idx, &b) in self.big.iter().flat_map(|d| d.iter()).enumerate() {
+            memory[big + idx] = b;
+        }
+    }
+}
+
+/// Formats built-in fonts by name and others as `custom:` followed by their
+/// bytes in hex, so that movies can restore them.
+impl fmt::Display for Font {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.name {
+            return write!(f, "{}", name);
+        }
+        write!(f, "custom:")?;
+        let small = self.small.iter().flat_map(|d| d.iter());
+        let big = self.big.iter().flat_map(|d| d.iter());
+        for b in small.chain(big) {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Font {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("custom:") {
+            if hex.len() % 2 != 0 {
+                return Err("invalid custom font".to_string());
+            }
+            let bytes = (0..hex.len() / 2)
+                .map(|idx| u8::from_str_radix(hex.get(idx * 2..idx * 2 + 2).unwrap_or(""), 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| "invalid custom font".to_string())?;
+            return Font::from_bytes(&bytes);
+        }
+        match BUILTIN.iter().find(|f| f.0 == s) {
+            Some(&(name, small)) => Ok(Font {
+                name: Some(name),
+                small,
+                big: SCHIP_BIG,
+            }),
+            None => {
+                let names = BUILTIN.iter().map(|f| f.0).collect::<Vec<_>>();
+                Err(format!(
+                    "unknown font `{}` (expected one of {})",
+                    s,
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_builtin_fonts_by_name() {
+        let font = " vip ".parse::<Font>().unwrap();
+        assert_eq!(font.name, Some("vip"));
+        assert_eq!(font.small, VIP);
+        assert_eq!(font.big, SCHIP_BIG);
+        assert_eq!(font.to_string(), "vip");
+        assert_eq!("default".parse::<Font>(), Ok(Font::default()));
+        assert!("chip48".parse::<Font>().unwrap_err().contains("fish"));
+    }
+
+    #[test]
+    fn reads_custom_fonts_back() {
+        let data = (0..SMALL_SIZE as u8).collect::<Vec<_>>();
+        let font = Font::from_bytes(&data).unwrap();
+        assert_eq!(font.name, None);
+        assert_eq!(font.small[1], [5, 6, 7, 8, 9]);
+        // a file with only the small font keeps the big one
+        assert_eq!(font.big, SCHIP_BIG);
+        assert_eq!(font.to_string().parse::<Font>(), Ok(font));
+
+        let mut data = vec![0xAA; SMALL_SIZE + BIG_SIZE];
+        data[SMALL_SIZE] = 0x55;
+        let font = Font::from_bytes(&data).unwrap();
+        assert_eq!(font.big[0][0], 0x55);
+        assert_eq!(font.to_string().parse::<Font>(), Ok(font));
+    }
+
+    #[test]
+    fn rejects_fonts_of_the_wrong_size() {
+        assert!(Font::from_bytes(&[0; SMALL_SIZE - 1]).is_err());
+        assert!(Font::from_bytes(&[0; SMALL_SIZE + 1]).is_err());
+        assert!("custom:".parse::<Font>().is_err());
+        assert!(format!("custom:{}0", "00".repeat(SMALL_SIZE))
+            .parse::<Font>()
+            .is_err());
+        assert!(format!("custom:{}", "0G".repeat(SMALL_SIZE))
+            .parse::<Font>()
+            .is_err());
+    }
+
+    #[test]
+    fn writes_both_fonts_into_memory() {
+        let mut memory = vec![0; 0x300];
+        Font::default().write(&mut memory, 0x100, 0x200);
+        assert_eq!(memory[0x100..0x105], FONTS[0]);
+        assert_eq!(memory[0x200 + 10..0x200 + 20], SCHIP_BIG[1]);
+        assert_eq!(memory[0xFF], 0);
+    }
+}
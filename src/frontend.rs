@@ -0,0 +1,641 @@
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use capture::{Capture, Screenshots};
+use filter::{Filter, Frame, FrameFilter};
+use interpreter::{Backend, Fault, FrameBudget};
+use movie::Movie;
+use watch::RomWatcher;
+use CHIP8_HEIGHT;
+use CHIP8_WIDTH;
+
+pub type Framebuffer = [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT];
+
+/// Result of polling the input of a frontend once per frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Poll {
+    Keys([bool; 16]),
+    Quit,
+}
+
+/// Emulator controls bound to host keys, reported next to the CHIP-8 keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    NextPalette,
+    ToggleFullscreen,
+    Screenshot,
+    Mute,
+    Pause,
+    /// Runs a single frame while paused.
+    FrameAdvance,
+    Faster,
+    Slower,
+    /// Runs as fast as possible for as long as it is held.
+    Turbo,
+    /// Goes back to the ROM browser, when the ROM was started from it.
+    Menu,
+    /// Not bindable, sent when the window needs to be drawn again, e.g.
+    /// after it was resized.
+    Redraw,
+}
+
+impl Hotkey {
+    /// The hotkeys that can be bound to host keys.
+    pub const ALL: [Hotkey; 10] = [
+        Hotkey::NextPalette,
+        Hotkey::ToggleFullscreen,
+        Hotkey::Screenshot,
+        Hotkey::Mute,
+        Hotkey::Pause,
+        Hotkey::FrameAdvance,
+        Hotkey::Faster,
+        Hotkey::Slower,
+        Hotkey::Turbo,
+        Hotkey::Menu,
+    ];
+
+    /// Name used for the hotkey in the `[hotkeys]` config section.
+    pub fn name(self) -> &'static str {
+        match self {
+            Hotkey::NextPalette => "next_palette",
+            Hotkey::ToggleFullscreen => "fullscreen",
+            Hotkey::Screenshot => "screenshot",
+            Hotkey::Mute => "mute",
+            Hotkey::Pause => "pause",
+            Hotkey::FrameAdvance => "frame_advance",
+            Hotkey::Faster => "faster",
+            Hotkey::Slower => "slower",
+            Hotkey::Turbo => "turbo",
+            Hotkey::Menu => "menu",
+            Hotkey::Redraw => "redraw",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        Hotkey::ALL.iter().cloned().find(|h| h.name() == name)
+    }
+}
+
+/// How fast the emulator runs, for displays to show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// Instructions run during the last second.
+    pub instructions_per_second: u32,
+    /// How much every 60 Hz frame runs.
+    pub budget: FrameBudget,
+    pub paused: bool,
+    pub turbo: bool,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.paused {
+            return write!(f, "paused");
+        }
+        write!(f, "{} Hz", self.instructions_per_second)?;
+        if self.turbo {
+            write!(f, ", fast-forward")
+        } else {
+            write!(f, ", {}", self.budget)
+        }
+    }
+}
+
+pub trait Display {
+    fn present(&mut self, frame: &Frame);
+
+    /// Shortest time between two presents, usually the refresh period of
+    /// the output device. `None` presents every change.
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Handles the hotkeys meant for the display, such as palette changes.
+    fn hotkey(&mut self, _hotkey: Hotkey) {}
+
+    /// Shows the speed of the emulator, which is reported every second and
+    /// whenever it is paused or its speed changes.
+    fn show_status(&mut self, _status: &Status) {}
+
+    /// Shows a short message, e.g. after a hotkey was pressed.
+    fn show_message(&mut self, _message: &str) {}
+
+    /// Called once per frame, paused or not, for displays that change on
+    /// their own, e.g. to take down messages after a while.
+    fn update(&mut self) {}
+}
+
+pub trait Input {
+    fn poll(&mut self) -> Poll;
+
+    /// Like `poll`, but called instead of it while the emulator is paused
+    /// so that inputs replaying recorded frames do not skip any.
+    fn peek(&mut self) -> Poll {
+        self.poll()
+    }
+
+    /// Hotkeys pressed since the last call.
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+
+    /// Hotkeys held down at the last poll, for those that act for as long
+    /// as they are held.
+    fn held_hotkeys(&self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+}
+
+pub trait Beeper {
+    /// Turns the tone on or off. `pattern` is the 128 bit XO-CHIP audio
+    /// pattern; `None` asks for the plain CHIP-8 beep.
+    fn set(&mut self, on: bool, pattern: Option<&[u8; 16]>);
+
+    /// Handles the hotkeys meant for the audio, such as muting.
+    fn hotkey(&mut self, _hotkey: Hotkey) {}
+
+    /// Called at the end of every emulated 60 Hz frame, for beepers that
+    /// render the audio on emulated time.
+    fn end_frame(&mut self) {}
+
+    /// Called once the run is over, e.g. to complete a file.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Display + ?Sized> Display for Box<T> {
+    fn present(&mut self, frame: &Frame) {
+        (**self).present(frame)
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        (**self).refresh_interval()
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        (**self).hotkey(hotkey)
+    }
+
+    fn show_status(&mut self, status: &Status) {
+        (**self).show_status(status)
+    }
+
+    fn show_message(&mut self, message: &str) {
+        (**self).show_message(message)
+    }
+
+    fn update(&mut self) {
+        (**self).update()
+    }
+}
+
+impl<T: Input + ?Sized> Input for Box<T> {
+    fn poll(&mut self) -> Poll {
+        (**self).poll()
+    }
+
+    fn peek(&mut self) -> Poll {
+        (**self).peek()
+    }
+
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        (**self).hotkeys()
+    }
+
+    fn held_hotkeys(&self) -> Vec<Hotkey> {
+        (**self).held_hotkeys()
+    }
+}
+
+impl<T: Beeper + ?Sized> Beeper for Box<T> {
+    fn set(&mut self, on: bool, pattern: Option<&[u8; 16]>) {
+        (**self).set(on, pattern)
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        (**self).hotkey(hotkey)
+    }
+
+    fn end_frame(&mut self) {
+        (**self).end_frame()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Feeds the frames of a movie to the interpreter. Once the movie runs out
+/// the wrapped input takes over, or the run ends when there is none.
+pub struct Playback<I> {
+    frames: ::std::vec::IntoIter<[bool; 16]>,
+    fallback: Option<I>,
+}
+
+impl<I: Input> Playback<I> {
+    pub fn new(movie: Movie, fallback: Option<I>) -> Self {
+        Playback {
+            frames: movie.frames.into_iter(),
+            fallback,
+        }
+    }
+}
+
+impl<I: Input> Input for Playback<I> {
+    fn poll(&mut self) -> Poll {
+        // the fallback is polled even while the movie plays so that the
+        // user can still quit
+        let live = self.fallback.as_mut().map(|input| input.poll());
+        if live == Some(Poll::Quit) {
+            return Poll::Quit;
+        }
+        match (self.frames.next(), live) {
+            (Some(keys), _) => Poll::Keys(keys),
+            (None, Some(live)) => live,
+            (None, None) => Poll::Quit,
+        }
+    }
+
+    fn peek(&mut self) -> Poll {
+        match self.fallback.as_mut().map(|input| input.peek()) {
+            Some(Poll::Quit) => Poll::Quit,
+            _ => Poll::Keys([false; 16]),
+        }
+    }
+
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        self.fallback
+            .as_mut()
+            .map_or_else(Vec::new, |input| input.hotkeys())
+    }
+
+    fn held_hotkeys(&self) -> Vec<Hotkey> {
+        self.fallback
+            .as_ref()
+            .map_or_else(Vec::new, |input| input.held_hotkeys())
+    }
+}
+
+/// Drives a CHIP-8 `Backend` with any combination of display, input and
+/// audio frontends, one `tick` per frame.
+///
+/// The display is only presented to once per refresh interval, however many
+/// times the framebuffer changed in between, so that the emulation speed
+/// does not depend on how expensive drawing is.
+///
+/// Hotkeys pause the emulator, step it frame by frame while paused, and
+/// change its speed by running more or less of the program per frame.
+pub struct Emulator<D, I, A> {
+    interpreter: Box<dyn Backend>,
+    display: D,
+    input: I,
+    beeper: A,
+    frame_delay: Duration,
+    /// Movies only hold the speed they started at, so it cannot change
+    /// while one is recorded or played.
+    speed_locked: bool,
+    recording: Option<Movie>,
+    filter: FrameFilter,
+    capture: Option<Capture>,
+    screenshots: Option<Screenshots>,
+    vram_dirty: bool,
+    last_present: Option<Instant>,
+    speed_window: (Instant, u32),
+    status: Status,
+    /// Whether the menu hotkey ends the run, and whether it did.
+    menu: bool,
+    left_for_menu: bool,
+    /// Reloads the ROM when it changes.
+    watcher: Option<RomWatcher>,
+    /// Why the program stopped, until it runs again.
+    fault: Option<Fault>,
+}
+
+impl<D: Display, I: Input, A: Beeper> Emulator<D, I, A> {
+    pub fn new(interpreter: Box<dyn Backend>, display: D, input: I, beeper: A) -> Self {
+        let frame_delay = interpreter.frame_delay();
+        let budget = interpreter.frame_budget();
+        Emulator {
+            interpreter,
+            display,
+            input,
+            beeper,
+            frame_delay,
+            speed_locked: false,
+            recording: None,
+            filter: FrameFilter::new(Filter::None),
+            capture: None,
+            screenshots: None,
+            vram_dirty: false,
+            last_present: None,
+            speed_window: (Instant::now(), 0),
+            status: Status {
+                instructions_per_second: 0,
+                budget,
+                paused: false,
+                turbo: false,
+            },
+            menu: false,
+            left_for_menu: false,
+            watcher: None,
+            fault: None,
+        }
+    }
+
+    /// Time to sleep after every frame, zero runs as fast as possible.
+    pub fn set_frame_delay(&mut self, delay: Duration) {
+        self.frame_delay = delay;
+    }
+
+    /// Appends the keys of every following frame to `movie`.
+    pub fn record(&mut self, movie: Movie) {
+        self.recording = Some(movie);
+        self.speed_locked = true;
+    }
+
+    /// Keeps the speed hotkeys from changing the speed, e.g. while the
+    /// input plays a movie back.
+    pub fn lock_speed(&mut self) {
+        self.speed_locked = true;
+    }
+
+    /// Filter applied to every frame before it is presented.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = FrameFilter::new(filter);
+        self.filter.push(self.interpreter.vram());
+    }
+
+    pub fn take_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    /// Writes the frame shown at the end of every 60 Hz frame to
+    /// `capture`.
+    pub fn capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// Ends the capture and the audio, flushing what was not written yet.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(capture) = self.capture.take() {
+            capture.finish()?;
+        }
+        self.beeper.finish()
+    }
+
+    /// Where the screenshot hotkey saves its images.
+    pub fn set_screenshots(&mut self, screenshots: Screenshots) {
+        self.screenshots = Some(screenshots);
+    }
+
+    /// Lets the menu hotkey end the run, for ROMs started from the ROM
+    /// browser.
+    pub fn enable_menu(&mut self) {
+        self.menu = true;
+    }
+
+    /// Why the program stopped running, if it did.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Whether the run ended because the player asked for the menu.
+    pub fn left_for_menu(&self) -> bool {
+        self.left_for_menu
+    }
+
+    /// Reloads the ROM whenever `watcher` sees it change, into a reset
+    /// interpreter unless the watcher keeps the state.
+    pub fn watch(&mut self, watcher: RomWatcher) {
+        self.watcher = Some(watcher);
+    }
+
+    fn reload(&mut self) {
+        let (result, keep_state) = match self.watcher {
+            Some(ref mut watcher) => match watcher.poll() {
+                Some(result) => (result, watcher.keep_state),
+                None => return,
+            },
+            None => return,
+        };
+        let result = result.and_then(|program| {
+            if keep_state {
+                self.interpreter.load(&program)?;
+                return Ok("rom patched");
+            }
+            // checked before the reset, which cannot be taken back
+            self.interpreter.check_program(&program)?;
+            self.interpreter.reset();
+            self.interpreter.load(&program)?;
+            self.filter.push(self.interpreter.vram());
+            self.vram_dirty = true;
+            self.flush();
+            Ok("rom reloaded")
+        });
+        match result {
+            Ok(message) => self.display.show_message(message),
+            Err(e) => {
+                eprintln!("cannot reload the ROM: {}", e);
+                self.display.show_message(&format!("reload failed: {}", e));
+            }
+        }
+    }
+
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    /// Hands the frontends back, e.g. to run another ROM with them.
+    pub fn into_frontends(self) -> (D, I, A) {
+        (self.display, self.input, self.beeper)
+    }
+
+    /// The frame last presented, after filtering.
+    pub fn frame(&self) -> &Frame {
+        self.filter.frame()
+    }
+
+    fn screenshot(&mut self) {
+        if let Some(ref screenshots) = self.screenshots {
+            match screenshots.save(self.filter.frame()) {
+                Ok(path) => {
+                    eprintln!("saved screenshot {}", path.display());
+                    self.display.show_message("screenshot saved");
+                }
+                Err(e) => {
+                    eprintln!("cannot save screenshot: {}", e);
+                    self.display.show_message("screenshot failed");
+                }
+            }
+        }
+    }
+
+    fn change_speed(&mut self, faster: bool) {
+        if self.speed_locked {
+            self.display.show_message("speed is fixed by the movie");
+            return;
+        }
+        let budget = self.interpreter.frame_budget().scaled(faster);
+        self.interpreter.set_frame_budget(budget);
+        // a zero delay runs as fast as possible and stays that way
+        if self.frame_delay != Duration::from_millis(0) {
+            self.frame_delay = self.interpreter.frame_delay();
+        }
+        self.status.budget = self.interpreter.frame_budget();
+        self.display.show_message(&self.status.budget.to_string());
+    }
+
+    /// Runs one frame, returning `false` once the input asks to quit or the
+    /// player goes back to the menu.
+    pub fn step(&mut self) -> bool {
+        let poll = if self.status.paused {
+            self.input.peek()
+        } else {
+            self.input.poll()
+        };
+        let mut keys = match poll {
+            Poll::Keys(keys) => keys,
+            Poll::Quit => return false,
+        };
+
+        let before = self.status;
+        let mut advance = false;
+        for hotkey in self.input.hotkeys() {
+            match hotkey {
+                Hotkey::Screenshot => self.screenshot(),
+                Hotkey::Pause => self.status.paused = !self.status.paused,
+                Hotkey::FrameAdvance => advance = self.status.paused,
+                Hotkey::Faster => self.change_speed(true),
+                Hotkey::Slower => self.change_speed(false),
+                Hotkey::Menu if self.menu => {
+                    self.left_for_menu = true;
+                    return false;
+                }
+                _ => {
+                    self.display.hotkey(hotkey);
+                    self.beeper.hotkey(hotkey);
+                }
+            }
+        }
+        self.status.turbo = self.input.held_hotkeys().contains(&Hotkey::Turbo);
+        if self.status != before {
+            self.display.show_status(&self.status);
+        }
+        self.display.update();
+        self.reload();
+
+        if self.status.paused {
+            if !advance {
+                self.beeper.set(false, None);
+                return true;
+            }
+            // the frame run on advance takes its keys from a real poll
+            keys = match self.input.poll() {
+                Poll::Keys(keys) => keys,
+                Poll::Quit => return false,
+            };
+        }
+        self.tick(keys);
+        true
+    }
+
+    fn tick(&mut self, keys: [bool; 16]) {
+        if let Some(ref mut movie) = self.recording {
+            movie.record(keys);
+        }
+
+        let output = match self.interpreter.tick(keys) {
+            Ok(output) => output,
+            Err(fault) => {
+                // the display stays as it was, so that the player or the
+                // watcher can fix the program
+                if self.fault.is_none() {
+                    eprintln!("the program stopped: {}", fault);
+                    self.display.show_message(&format!("stopped: {}", fault));
+                    self.fault = Some(fault);
+                }
+                self.beeper.set(false, None);
+                return;
+            }
+        };
+        self.fault = None;
+        let instructions = output.instructions;
+
+        if output.vram_changed {
+            self.filter.push(output.vram);
+            self.vram_dirty = true;
+        }
+        if output.end_of_frame {
+            self.filter.end_frame();
+        }
+        let due = match (self.display.refresh_interval(), self.last_present) {
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+            _ => true,
+        };
+        if (self.vram_dirty || self.filter.fading()) && due {
+            self.display.present(self.filter.apply());
+            self.vram_dirty = false;
+            self.last_present = Some(Instant::now());
+        }
+        self.beeper.set(output.beep, None);
+
+        let result = match self.capture {
+            Some(ref mut capture) if output.end_of_frame => capture.frame(self.filter.frame()),
+            _ => Ok(()),
+        };
+        if output.end_of_frame {
+            self.beeper.end_frame();
+        }
+        if let Err(e) = result {
+            eprintln!("capture stopped: {}", e);
+            self.capture = None;
+        }
+
+        let (since, counted) = self.speed_window;
+        if since.elapsed() >= Duration::from_secs(1) {
+            self.status.instructions_per_second = counted + instructions;
+            self.display.show_status(&self.status);
+            self.speed_window = (Instant::now(), 0);
+        } else {
+            self.speed_window = (since, counted + instructions);
+        }
+    }
+
+    /// Presents the framebuffer if it changed since the last present.
+    pub fn flush(&mut self) {
+        if self.vram_dirty {
+            self.display.present(self.filter.apply());
+            self.vram_dirty = false;
+            self.last_present = Some(Instant::now());
+        }
+    }
+
+    /// Runs frames until the input asks to quit, returning how many ran.
+    ///
+    /// Frames are paced against a deadline rather than by sleeping a fixed
+    /// time after each, so that the time spent running them does not slow
+    /// the emulator down.
+    pub fn run(&mut self) -> u64 {
+        let mut frames = 0;
+        let mut deadline = Instant::now();
+        while self.step() {
+            frames += 1;
+            if self.frame_delay == Duration::from_millis(0) || self.status.turbo {
+                continue;
+            }
+            deadline += self.frame_delay;
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            } else if now - deadline > Duration::from_millis(100) {
+                // too far behind, e.g. after fast-forwarding, to catch up
+                deadline = now;
+            }
+        }
+        self.flush();
+        frames
+    }
+}
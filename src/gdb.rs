@@ -0,0 +1,491 @@
+//! A stub speaking the GDB remote serial protocol, so that gdb or an IDE
+//! can debug a program running on `Interpreter`.
+//!
+//! Registers are numbered V0-VF, I, PC, SP, DT and ST, 0 to 20, and sent
+//! big endian like CHIP-8 words: one byte each but for the two of I and
+//! PC. The address space is the memory of the interpreter.
+//!
+//! Without `vip_timing`, every tick runs one instruction, so that a step
+//! is one instruction and breakpoints hit before any instruction. With it
+//! a step runs a whole frame, and breakpoints are only seen between frames.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use interpreter::{Fault, Interpreter, Registers};
+use CHIP8_HEIGHT;
+use CHIP8_WIDTH;
+
+/// The register layout for gdb, which has no CHIP-8 architecture of its
+/// own.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers, and bytes they take in a `g` packet.
+const REGISTERS: usize = 21;
+const REGISTER_BYTES: usize = 23;
+
+/// Ticks run between looking for a ^C from gdb while the program runs.
+const INTERRUPT_INTERVAL: u64 = 1000;
+
+/// Signals for the stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// The byte gdb sends to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|idx| {
+            s.get(idx..idx + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// The `ADDR,LENGTH` of memory packets.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, ',');
+    let address = parse_number(parts.next()?)?;
+    let length = parse_number(parts.next()?)?;
+    Some((address, length))
+}
+
+/// Register `n` as it is sent to gdb.
+fn register_bytes(registers: &Registers, n: usize) -> Option<Vec<u8>> {
+    let bytes = match n {
+        0..=15 => vec![registers.v[n]],
+        16 => vec![(registers.i >> 8) as u8, registers.i as u8],
+        17 => vec![(registers.pc >> 8) as u8, registers.pc as u8],
+        18 => vec![registers.sp as u8],
+        19 => vec![registers.dt],
+        20 => vec![registers.st],
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// Sets register `n` from the bytes gdb sent, `false` when they do not fit.
+fn set_register(registers: &mut Registers, n: usize, bytes: &[u8]) -> bool {
+    let word = || (usize::from(bytes[0]) << 8) | usize::from(bytes[1]);
+    match (n, bytes.len()) {
+        (0..=15, 1) => registers.v[n] = bytes[0],
+        (16, 2) => registers.i = word(),
+        (17, 2) => registers.pc = word(),
+        (18, 1) => registers.sp = usize::from(bytes[0]),
+        (19, 1) => registers.dt = bytes[0],
+        (20, 1) => registers.st = bytes[0],
+        _ => return false,
+    }
+    true
+}
+
+/// Why the program stopped, as the signal of a stop reply.
+fn fault_signal(fault: Fault) -> u8 {
+    match fault {
+        Fault::UnsupportedInstruction { .. } => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+/// Serves one gdb connection, running the interpreter only when gdb asks
+/// it to continue or step.
+pub struct Stub {
+    interpreter: Interpreter,
+    stream: TcpStream,
+    /// Bytes received but not parsed yet.
+    received: VecDeque<u8>,
+    breakpoints: Vec<usize>,
+    /// Keys held down while the program runs, set with `monitor keys`.
+    keys: [bool; 16],
+    signal: u8,
+}
+
+impl Stub {
+    pub fn new(interpreter: Interpreter, stream: TcpStream) -> io::Result<Stub> {
+        stream.set_nodelay(true)?;
+        Ok(Stub {
+            interpreter,
+            stream,
+            received: VecDeque::new(),
+            breakpoints: Vec::new(),
+            keys: [false; 16],
+            signal: SIGTRAP,
+        })
+    }
+
+    /// Answers packets until gdb detaches, kills the program or closes the
+    /// connection.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            if !self.handle(&packet)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The next byte from gdb, `None` once the connection is closed.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.received.is_empty() {
+            let mut buffer = [0u8; 1024];
+            let count = self.stream.read(&mut buffer)?;
+            self.received.extend(&buffer[..count]);
+        }
+        Ok(self.received.pop_front())
+    }
+
+    /// Reads the next packet and acknowledges it, asking again for the
+    /// ones that arrive damaged.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                // acknowledgements, and ^C while the program is stopped
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => {
+                        checksum = checksum.wrapping_add(b);
+                        data.push(b);
+                    }
+                }
+            }
+            let mut sent = [0u8; 2];
+            for digit in sent.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b) => *digit = b,
+                }
+            }
+            let expected = String::from_utf8_lossy(&sent);
+            if u8::from_str_radix(&expected, 16).ok() != Some(checksum) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(unescape(&data)));
+        }
+    }
+
+    /// Sends `data` as a packet until gdb acknowledges it.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            loop {
+                match self.read_byte()? {
+                    None | Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Whether gdb sent a ^C, without waiting for it.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.received.is_empty() {
+            let mut buffer = [0u8; 1024];
+            self.stream.set_nonblocking(true)?;
+            let result = self.stream.read(&mut buffer);
+            self.stream.set_nonblocking(false)?;
+            match result {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "gdb closed the connection",
+                    ))
+                }
+                Ok(count) => self.received.extend(&buffer[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match self.received.iter().position(|&b| b == INTERRUPT) {
+            Some(idx) => {
+                self.received.drain(..=idx);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Answers `packet`, returning `false` when the session is over.
+    fn handle(&mut self, packet: &str) -> io::Result<bool> {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => format!("S{:02x}", self.signal),
+            "g" => {
+                let registers = self.interpreter.registers();
+                (0..REGISTERS)
+                    .filter_map(|n| register_bytes(&registers, n))
+                    .map(|bytes| hex(&bytes))
+                    .collect()
+            }
+            "G" => self.write_registers(args),
+            "p" => {
+                let registers = self.interpreter.registers();
+                match parse_number(args).and_then(|n| register_bytes(&registers, n)) {
+                    Some(bytes) => hex(&bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" | "s" => {
+                if let Some(pc) = parse_number(args) {
+                    let registers = self.interpreter.registers();
+                    self.interpreter
+                        .set_registers(Registers { pc, ..registers });
+                }
+                self.resume(command == "s")?
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "D" => {
+                self.send("OK")?;
+                return Ok(false);
+            }
+            "k" => return Ok(false),
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(true)
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, length)) => {
+                    let start = offset.min(TARGET_XML.len());
+                    let end = start.saturating_add(length).min(TARGET_XML.len());
+                    let last = if end == TARGET_XML.len() { "l" } else { "m" };
+                    format!("{}{}", last, &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else if let Some(command) = query.strip_prefix("Rcmd,") {
+            match parse_hex(command) {
+                Some(command) => hex(self.monitor(&String::from_utf8_lossy(&command)).as_bytes()),
+                None => "E01".to_string(),
+            }
+        } else {
+            match query {
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                "Symbol::" => "OK".to_string(),
+                _ => String::new(),
+            }
+        }
+    }
+
+    /// The `monitor` commands, which stand in for the keypad and display.
+    fn monitor(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("keys") => {
+                let mut keys = [false; 16];
+                for word in words {
+                    match u8::from_str_radix(word, 16) {
+                        Ok(key) if key < 16 => keys[key as usize] = true,
+                        _ => return format!("invalid key `{}`\n", word),
+                    }
+                }
+                self.keys = keys;
+                let held = (0..16)
+                    .filter(|&key| keys[key])
+                    .map(|key| format!(" {:X}", key))
+                    .collect::<String>();
+                if held.is_empty() {
+                    "no keys held\n".to_string()
+                } else {
+                    format!("holding keys{}\n", held)
+                }
+            }
+            Some("screen") => {
+                let mut screen = String::with_capacity((CHIP8_WIDTH + 1) * CHIP8_HEIGHT);
+                for row in self.interpreter.vram().iter() {
+                    screen.extend(row.iter().map(|&p| if p == 1 { '#' } else { '.' }));
+                    screen.push('\n');
+                }
+                screen
+            }
+            _ => "monitor keys [KEY...]  hold down these hex keys while the program runs\n\
+                  monitor screen         show the display\n"
+                .to_string(),
+        }
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = match parse_hex(args) {
+            Some(ref bytes) if bytes.len() == REGISTER_BYTES => bytes.clone(),
+            _ => return "E01".to_string(),
+        };
+        let mut registers = self.interpreter.registers();
+        let mut offset = 0;
+        for n in 0..REGISTERS {
+            let size = register_bytes(&registers, n).map_or(0, |b| b.len());
+            set_register(&mut registers, n, &bytes[offset..offset + size]);
+            offset += size;
+        }
+        self.interpreter.set_registers(registers);
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let n = parts.next().and_then(parse_number);
+        let bytes = parts.next().and_then(parse_hex);
+        let mut registers = self.interpreter.registers();
+        match (n, bytes) {
+            (Some(n), Some(bytes)) if set_register(&mut registers, n, &bytes) => {
+                self.interpreter.set_registers(registers);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Reads what there is of the range, failing only when none of it is
+    /// in memory.
+    fn read_memory(&self, args: &str) -> String {
+        let memory = self.interpreter.memory();
+        match parse_range(args) {
+            Some((address, length)) if address < memory.len() => {
+                let end = address.saturating_add(length).min(memory.len());
+                hex(&memory[address..end])
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_range);
+        let bytes = parts.next().and_then(parse_hex);
+        let memory = self.interpreter.memory_mut();
+        match (range, bytes) {
+            (Some((address, length)), Some(bytes))
+                if bytes.len() == length && address.saturating_add(length) <= memory.len() =>
+            {
+                memory[address..address + length].copy_from_slice(&bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Sets or clears a breakpoint. Software and hardware breakpoints are
+    /// the same here, watchpoints are not supported.
+    fn breakpoint(&mut self, set: bool, args: &str) -> String {
+        let mut parts = args.splitn(3, ',');
+        let kind = parts.next();
+        let address = parts.next().and_then(parse_number);
+        match (kind, address) {
+            (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                self.breakpoints.retain(|&b| b != address);
+                if set {
+                    self.breakpoints.push(address);
+                }
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Runs a tick, or ticks until a breakpoint, a fault or a ^C, returning
+    /// the stop reply.
+    fn resume(&mut self, step: bool) -> io::Result<String> {
+        let mut ticks = 0u64;
+        self.signal = loop {
+            if let Err(fault) = self.interpreter.tick(self.keys) {
+                let message = format!("the program stopped: {}\n", fault);
+                self.send(&format!("O{}", hex(message.as_bytes())))?;
+                break fault_signal(fault);
+            }
+            ticks += 1;
+            let pc = self.interpreter.registers().pc;
+            if step || self.breakpoints.contains(&pc) {
+                break SIGTRAP;
+            }
+            if ticks.is_multiple_of(INTERRUPT_INTERVAL) && self.interrupted()? {
+                break SIGINT;
+            }
+        };
+        Ok(format!("S{:02x}", self.signal))
+    }
+}
+
+/// Undoes the `}` escapes of binary packet data.
+fn unescape(data: &[u8]) -> String {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &b in data {
+        if escaped {
+            bytes.push(b ^ 0x20);
+            escaped = false;
+        } else if b == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
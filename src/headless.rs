@@ -0,0 +1,77 @@
+use filter::Frame;
+use frontend::{Beeper, Display, Framebuffer, Input, Poll};
+use CHIP8_HEIGHT;
+use CHIP8_WIDTH;
+
+/// Display that keeps a copy of the last presented frame instead of showing
+/// it anywhere.
+pub struct Headless {
+    framebuffer: Framebuffer,
+}
+
+impl Default for Headless {
+    fn default() -> Self {
+        Headless {
+            framebuffer: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+        }
+    }
+}
+
+impl Headless {
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+}
+
+impl Display for Headless {
+    fn present(&mut self, frame: &Frame) {
+        self.framebuffer = frame.pixels;
+    }
+}
+
+/// Input with no key ever pressed.
+pub struct Idle;
+
+impl Input for Idle {
+    fn poll(&mut self) -> Poll {
+        Poll::Keys([false; 16])
+    }
+}
+
+/// Ends the run after a fixed number of frames of the wrapped input.
+pub struct FrameLimit<I> {
+    input: I,
+    frames_left: u64,
+}
+
+impl<I: Input> FrameLimit<I> {
+    pub fn new(input: I, frames: u64) -> Self {
+        FrameLimit {
+            input,
+            frames_left: frames,
+        }
+    }
+}
+
+impl<I: Input> Input for FrameLimit<I> {
+    fn poll(&mut self) -> Poll {
+        if self.frames_left == 0 {
+            return Poll::Quit;
+        }
+        self.frames_left -= 1;
+        self.input.poll()
+    }
+
+    fn peek(&mut self) -> Poll {
+        if self.frames_left == 0 {
+            return Poll::Quit;
+        }
+        self.input.peek()
+    }
+}
+
+pub struct Silence;
+
+impl Beeper for Silence {
+    fn set(&mut self, _on: bool, _pattern: Option<&[u8; 16]>) {}
+}
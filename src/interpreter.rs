@@ -2,6 +2,14 @@ use byteorder::{BigEndian, ByteOrder};
 use rand::thread_rng;
 use rand::Rng;
 use std::default::Default;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use config::Config;
+use font::{Font, BIG_SIZE as BIG_FONT_SIZE, SMALL_SIZE as FONT_SIZE};
 use CHIP8_HEIGHT;
 use CHIP8_RAM;
 use CHIP8_WIDTH;
@@ -43,10 +51,484 @@ pub const FONTS: [[u8; 5]; 16] = [
 
 const INSTR_SIZE: usize = 2;
 
+/// The delay and sound timers count down once per emulated frame, which
+/// lasts `Options::tickrate` ticks, or a single tick with `vip_timing`.
+pub const TIMER_RATE: u32 = 60;
+
+/// VIP machine cycles per 60 Hz frame that are left to the interpreter:
+/// 3668 in all, less about 1100 taken by the display DMA and interrupt.
+pub const VIP_FRAME_CYCLES: u32 = 2572;
+
+/// Instructions per 60 Hz frame unless told otherwise, close to the 500 Hz
+/// most interpreters default to.
+pub const DEFAULT_TICKRATE: u32 = 8;
+
+/// Highest number of instructions per frame the speed can be raised to.
+const MAX_TICKRATE: u32 = 1000;
+
+/// VIP machine cycles spent fetching and decoding every instruction.
+const VIP_FETCH_CYCLES: u32 = 40;
+
+/// Approximate VIP machine cycles `instr` takes, after the published
+/// timings of its interpreter. Costs that depend on the data, like the
+/// number of sprite bytes, are averaged out where they are small.
+fn vip_cycles(instr: u16) -> u32 {
+    let x = u32::from((instr >> 8) & 0xF);
+    let n = u32::from(instr & 0xF);
+    let execute = match (instr >> 12, instr & 0xFF) {
+        // clears the 256 bytes of the display one at a time
+        (0x0, 0xE0) => 24 + 256 * 8,
+        (0x0, 0xEE) => 10,
+        (0x0, _) => 0,
+        (0x1, _) => 12,
+        (0x2, _) => 26,
+        (0x3, _) | (0x4, _) => 10,
+        (0x5, _) | (0x9, _) => 14,
+        (0x6, _) => 6,
+        (0x7, _) => 10,
+        (0x8, _) => 44,
+        (0xA, _) => 12,
+        (0xB, _) => 22,
+        (0xC, _) => 36,
+        // shifts every sprite row into place and XORs it in
+        (0xD, _) => 26 + n * 46,
+        (0xE, _) => 14,
+        (0xF, 0x0A) => 20,
+        (0xF, 0x1E) => 16,
+        (0xF, 0x29) | (0xF, 0x30) => 20,
+        // converts by repeated subtraction
+        (0xF, 0x33) => 364,
+        (0xF, 0x55) | (0xF, 0x65) => 14 + 14 * (x + 1),
+        _ => 10,
+    };
+    VIP_FETCH_CYCLES + execute
+}
+
+/// Where programs and fonts go in memory. The defaults are those of the
+/// COSMAC VIP, with the fonts, which lived in its ROM, moved into the
+/// otherwise unused interpreter area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Where programs are loaded and start running, 0x600 on the ETI-660.
+    pub load_address: usize,
+    /// Address of the small hex font, which FX29 points I into.
+    pub font_address: usize,
+    /// Address of the SCHIP big font, which FX30 points I into.
+    pub big_font_address: usize,
+    /// Bytes of memory, from 4 KB up to the 64 KB of XO-CHIP.
+    pub size: usize,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        MemoryLayout {
+            load_address: 0x200,
+            font_address: 0,
+            big_font_address: FONT_SIZE,
+            size: CHIP8_RAM,
+        }
+    }
+}
+
+impl MemoryLayout {
+    /// The `[memory]` config sections, per-ROM sections winning over the
+    /// global one.
+    pub fn from_config(config: &Config, rom_name: &str, rom_hash: &str) -> io::Result<Self> {
+        let mut layout = MemoryLayout::default();
+        let entries = config.entries("memory", rom_name, rom_hash);
+        for &entry in entries.iter() {
+            // read by `Font::from_config`
+            if entry.key == "font" {
+                continue;
+            }
+            let address = parse_address(&entry.value).ok_or_else(|| {
+                config.error(entry, &format!("invalid address `{}`", entry.value))
+            })?;
+            match entry.key.as_str() {
+                "load_address" => layout.load_address = address,
+                "font_address" => layout.font_address = address,
+                "big_font_address" => layout.big_font_address = address,
+                "size" => layout.size = address,
+                _ => {
+                    return Err(
+                        config.error(entry, &format!("unknown memory setting `{}`", entry.key))
+                    )
+                }
+            }
+        }
+        match (layout.check(), entries.last()) {
+            (Err(e), Some(entry)) => Err(config.error(entry, &e)),
+            _ => Ok(layout),
+        }
+    }
+
+    /// Makes sure everything fits in memory.
+    pub fn check(&self) -> Result<(), String> {
+        if self.size < CHIP8_RAM || self.size > 0x10000 {
+            return Err(format!(
+                "memory size {:#X} is not between {:#X} and 0x10000",
+                self.size, CHIP8_RAM
+            ));
+        }
+        if self.load_address >= self.size {
+            return Err(format!(
+                "load address {:#X} is outside the memory",
+                self.load_address
+            ));
+        }
+        if self.font_address > self.size - FONT_SIZE
+            || self.big_font_address > self.size - BIG_FONT_SIZE
+        {
+            return Err("fonts do not fit in memory".to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks that `program` fits between the load address and the end of
+    /// memory.
+    pub fn check_program(&self, program: &[u8]) -> io::Result<()> {
+        if self.load_address + program.len() > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ROM of {} bytes does not fit in memory at {:#X}",
+                    program.len(),
+                    self.load_address
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `0x`-prefixed hex or decimal.
+fn parse_address(s: &str) -> Option<usize> {
+    let s = s.trim();
+    if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Formats the layout as `load=0x200,font=0x0,big_font=0x50,size=0x1000`,
+/// the format `FromStr` reads back.
+impl fmt::Display for MemoryLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "load={:#X},font={:#X},big_font={:#X},size={:#X}",
+            self.load_address, self.font_address, self.big_font_address, self.size
+        )
+    }
+}
+
+impl FromStr for MemoryLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut layout = MemoryLayout::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let mut parts = item.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let address = parts
+                .next()
+                .and_then(parse_address)
+                .ok_or_else(|| format!("invalid memory layout entry `{}`", item))?;
+            match key {
+                "load" => layout.load_address = address,
+                "font" => layout.font_address = address,
+                "big_font" => layout.big_font_address = address,
+                "size" => layout.size = address,
+                _ => return Err(format!("unknown memory layout entry `{}`", key)),
+            }
+        }
+        layout.check()?;
+        Ok(layout)
+    }
+}
+
+/// Behaviour differences between the original COSMAC VIP interpreter and
+/// the later CHIP-48/SCHIP ones. The defaults match what this interpreter
+/// has always done.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY and store the result in VX (VIP) instead of
+    /// shifting VX in place.
+    pub shift_vy: bool,
+    /// FX55/FX65 leave I pointing past the last register accessed (VIP).
+    pub load_store_inc_i: bool,
+    /// BNNN jumps to NNN + VX instead of NNN + V0 (SCHIP).
+    pub jump_vx: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0 (VIP).
+    pub vf_reset: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around.
+    pub clip_sprites: bool,
+    /// FX0A completes as soon as a key goes down, instead of waiting for it
+    /// to be released again like the VIP does.
+    pub wait_key_press: bool,
+    /// Every tick is a 60 Hz frame of the VIP, running as many instructions
+    /// as fit in its cycle budget instead of just one.
+    pub vip_timing: bool,
+    /// With `vip_timing`, DXYN waits for the next frame like on the VIP,
+    /// which drew sprites in the vertical blank.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub const NAMES: [&'static str; 8] = [
+        "shift_vy",
+        "load_store_inc_i",
+        "jump_vx",
+        "vf_reset",
+        "clip_sprites",
+        "wait_key_press",
+        "vip_timing",
+        "display_wait",
+    ];
+
+    /// Enables or disables the quirk called `name`, returning `false` when
+    /// no such quirk exists.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "shift_vy" => self.shift_vy = value,
+            "load_store_inc_i" => self.load_store_inc_i = value,
+            "jump_vx" => self.jump_vx = value,
+            "vf_reset" => self.vf_reset = value,
+            "clip_sprites" => self.clip_sprites = value,
+            "wait_key_press" => self.wait_key_press = value,
+            "vip_timing" => self.vip_timing = value,
+            "display_wait" => self.display_wait = value,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "shift_vy" => Some(self.shift_vy),
+            "load_store_inc_i" => Some(self.load_store_inc_i),
+            "jump_vx" => Some(self.jump_vx),
+            "vf_reset" => Some(self.vf_reset),
+            "clip_sprites" => Some(self.clip_sprites),
+            "wait_key_press" => Some(self.wait_key_press),
+            "vip_timing" => Some(self.vip_timing),
+            "display_wait" => Some(self.display_wait),
+            _ => None,
+        }
+    }
+}
+
+/// Formats the enabled quirks as a comma separated list, e.g.
+/// `shift_vy,jump_vx`, or `none` when every quirk is off.
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = Quirks::NAMES
+            .iter()
+            .filter(|name| self.get(name) == Some(true))
+            .cloned()
+            .collect::<Vec<_>>();
+        if enabled.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", enabled.join(","))
+        }
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quirks = Quirks::default();
+        if s.trim() == "none" {
+            return Ok(quirks);
+        }
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if !quirks.set(name, true) {
+                return Err(format!("unknown quirk: {}", name));
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+/// How much of the program a 60 Hz frame runs, which sets the speed of the
+/// emulator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameBudget {
+    /// Instructions per frame, one per tick.
+    Instructions(u32),
+    /// Machine cycles per frame, all run in a single tick.
+    Cycles(u32),
+}
+
+impl FrameBudget {
+    /// The next step up or down, about a quarter more or less and at least
+    /// one. Backends keep it within what they can run.
+    pub fn scaled(self, faster: bool) -> FrameBudget {
+        let step = |n: u32| {
+            if faster {
+                (n + n / 4).max(n + 1)
+            } else {
+                (n - n / 5).min(n - 1).max(1)
+            }
+        };
+        match self {
+            FrameBudget::Instructions(n) => FrameBudget::Instructions(step(n)),
+            FrameBudget::Cycles(n) => FrameBudget::Cycles(step(n)),
+        }
+    }
+}
+
+impl fmt::Display for FrameBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameBudget::Instructions(n) => write!(f, "{} instructions per frame", n),
+            FrameBudget::Cycles(n) => write!(f, "{} cycles per frame", n),
+        }
+    }
+}
+
+/// Everything needed to make two runs of the same ROM with the same input
+/// behave identically.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub quirks: Quirks,
+    /// Instructions per 60 Hz frame, unless `vip_timing` runs frames by
+    /// cycles.
+    pub tickrate: u32,
+    /// Seed for the CXNN random number generator.
+    pub seed: u64,
+    pub layout: MemoryLayout,
+    pub font: Font,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            quirks: Quirks::default(),
+            tickrate: DEFAULT_TICKRATE,
+            seed: thread_rng().gen(),
+            layout: MemoryLayout::default(),
+            font: Font::default(),
+        }
+    }
+}
+
+/// Why the interpreter cannot go on with a program. The instruction that
+/// faults is not run, so ticking again faults again until the program
+/// changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The opcode at `pc` is no CHIP-8 instruction.
+    UnsupportedInstruction { pc: usize, opcode: u16 },
+    /// A 2NNN at `pc` with all 16 stack entries in use.
+    StackOverflow { pc: usize },
+    /// A 00EE at `pc` with nothing on the stack.
+    StackUnderflow { pc: usize },
+    /// The instruction at `pc`, or the memory it uses, reaches `address`
+    /// past the end of memory.
+    OutOfMemory { pc: usize, address: usize },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::UnsupportedInstruction { pc, opcode } => {
+                write!(f, "unsupported instruction {:04X} at ${:03X}", opcode, pc)
+            }
+            Fault::StackOverflow { pc } => write!(f, "stack overflow at ${:03X}", pc),
+            Fault::StackUnderflow { pc } => {
+                write!(f, "return with an empty stack at ${:03X}", pc)
+            }
+            Fault::OutOfMemory { pc, address } => write!(
+                f,
+                "access to ${:03X} past the end of memory at ${:03X}",
+                address, pc
+            ),
+        }
+    }
+}
+
+impl Error for Fault {}
+
 pub struct State<'a> {
     pub vram: &'a [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
     pub vram_changed: bool,
     pub beep: bool,
+    /// The interpreter is blocked on FX0A.
+    pub waiting_for_key: bool,
+    /// Instructions run during the tick.
+    pub instructions: u32,
+    /// The tick ended a 60 Hz frame, which is when frontends sample the
+    /// display and the beeper for captures.
+    pub end_of_frame: bool,
+}
+
+/// The complete machine state of an `Interpreter`, for comparing runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub sp: usize,
+    pub stack: [usize; 16],
+    pub dt: u8,
+    pub st: u8,
+    pub memory: Vec<u8>,
+    pub vram: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+}
+
+/// The registers a debugger can see and change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+}
+
+/// A CHIP-8 implementation the frontends can drive, one tick per frame.
+pub trait Backend {
+    /// Runs a tick with the keys in `keyboard` held down, or reports why
+    /// the program cannot go on.
+    fn tick(&mut self, keyboard: [bool; 16]) -> Result<State<'_>, Fault>;
+
+    fn vram(&self) -> &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT];
+
+    /// Makes sure `program` fits where `load` puts it.
+    fn check_program(&self, program: &[u8]) -> io::Result<()>;
+
+    /// Writes `program` into memory without resetting anything else, or
+    /// fails like `check_program` when it does not fit.
+    fn load(&mut self, program: &[u8]) -> io::Result<()>;
+
+    /// Goes back to the state it was created in, before any program was
+    /// loaded.
+    fn reset(&mut self);
+
+    /// How long a tick stands for, so that frames run at 60 Hz.
+    fn frame_delay(&self) -> Duration;
+
+    /// How much a frame runs.
+    fn frame_budget(&self) -> FrameBudget;
+
+    /// Changes how much a frame runs, within what the backend supports. A
+    /// budget of the other kind is ignored.
+    fn set_frame_budget(&mut self, budget: FrameBudget);
+}
+
+/// Progress of an FX0A instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyWait {
+    Idle,
+    /// Waiting for any key to go down, the result goes into the register.
+    Press(usize),
+    /// A key went down, waiting for it to come back up.
+    Release(usize, usize),
 }
 
 pub struct Interpreter {
@@ -57,12 +539,24 @@ pub struct Interpreter {
     dt: u8,
     sp: usize,
     st: u8,
-    memory: [u8; CHIP8_RAM],
+    memory: Vec<u8>,
     vram: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
     vram_changed: bool,
     keyboard: [bool; 16],
-    keyboard_waiting: bool,
-    keyboard_register: usize,
+    key_wait: KeyWait,
+    quirks: Quirks,
+    layout: MemoryLayout,
+    options: Options,
+    rng: u64,
+    /// Instructions per frame, without `vip_timing`.
+    tickrate: u32,
+    /// Ticks run in the current frame, without `vip_timing`.
+    frame_tick: u32,
+    /// VIP machine cycles per frame, with `vip_timing`.
+    frame_cycles: u32,
+    /// VIP machine cycles left in the current frame, negative when the last
+    /// instruction ran over into the next one.
+    cycles: i32,
 }
 
 impl Default for Interpreter {
@@ -72,13 +566,22 @@ impl Default for Interpreter {
 }
 impl Interpreter {
     pub fn new() -> Interpreter {
-        let mut raw_memory = [0u8; CHIP8_RAM];
-        Interpreter::init_fonts(&mut raw_memory);
+        Interpreter::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Interpreter {
+        let layout = options.layout;
+        let mut raw_memory = vec![0u8; layout.size];
+        options.font.write(
+            &mut raw_memory,
+            layout.font_address,
+            layout.big_font_address,
+        );
         Interpreter {
             vx: [0u8; 16],
             stack: [0usize; 16],
             i: 0,
-            pc: 0x200,
+            pc: layout.load_address,
             dt: 0,
             sp: 0,
             st: 0,
@@ -86,68 +589,222 @@ impl Interpreter {
             vram: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
             vram_changed: false,
             keyboard: [false; 16],
-            keyboard_waiting: false,
-            keyboard_register: 0,
+            key_wait: KeyWait::Idle,
+            quirks: options.quirks,
+            layout,
+            options,
+            rng: Interpreter::scramble_seed(options.seed),
+            tickrate: options.tickrate.clamp(1, MAX_TICKRATE),
+            frame_tick: 0,
+            frame_cycles: VIP_FRAME_CYCLES,
+            cycles: 0,
         }
     }
 
-    pub fn init_fonts(memory: &mut [u8]) {
-        // place the fonts sprites in memory starting with reserved address
-        // 0x0000
-        for (idx, b) in FONTS.iter().flat_map(|arr| arr.iter()).enumerate() {
-            memory[idx] = *b;
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    pub fn vram(&self) -> &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT] {
+        &self.vram
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            v: self.vx,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            dt: self.dt,
+            st: self.st,
+            memory: self.memory.clone(),
+            vram: self.vram,
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.vx,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            dt: self.dt,
+            st: self.st,
         }
     }
 
+    /// Changes the registers, keeping SP within the stack.
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.vx = registers.v;
+        self.i = registers.i;
+        self.pc = registers.pc;
+        self.sp = registers.sp.min(self.stack.len());
+        self.dt = registers.dt;
+        self.st = registers.st;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    // splitmix64 step, so that small or zero seeds still give a usable
+    // xorshift state
+    fn scramble_seed(seed: u64) -> u64 {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) | 1
+    }
+
+    // xorshift64*, kept in-tree so that recorded movies replay identically
+    // regardless of the rand crate version
+    fn next_random(&mut self) -> u8 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
     fn byte_to_bits(byte: u8) -> [u8; 8] {
         let mut bits = [0u8; 8];
         let mut byte = byte;
-        for i in (0..bits.len()).into_iter().rev() {
+        for i in (0..bits.len()).rev() {
             bits[i] = byte & 0x1;
             byte >>= 1;
         }
         bits
     }
 
-    pub fn load(&mut self, program: &[u8]) {
-        assert!(program.len() + 0x200 <= 4096);
-        program
-            .iter()
-            .enumerate()
-            .for_each(|(idx, &v)| self.memory[0x200 + idx] = v);
+    /// Writes `program` into memory. Loading another one over it patches
+    /// the code but leaves the registers, timers and display alone. Fails
+    /// like `MemoryLayout::check_program` when the program does not fit.
+    pub fn load(&mut self, program: &[u8]) -> io::Result<()> {
+        self.layout.check_program(program)?;
+        let start = self.layout.load_address;
+        self.memory[start..start + program.len()].copy_from_slice(program);
+        Ok(())
     }
 
-    pub fn tick(&mut self, keyboard: [bool; 16]) -> State {
+    pub fn tick(&mut self, keyboard: [bool; 16]) -> Result<State<'_>, Fault> {
+        let previous = self.keyboard;
         self.keyboard = keyboard;
         self.vram_changed = false;
 
-        if self.keyboard_waiting {
-            let pos = self.keyboard.iter().position(|&v| v).unwrap();
-            self.vx[self.keyboard_register] = pos as u8;
-        } else {
+        // the timers count down at the start of every frame, and keep
+        // running while FX0A blocks
+        if self.quirks.vip_timing || self.frame_tick == 0 {
             if self.dt > 0 {
                 self.dt -= 1;
             }
             if self.st > 0 {
                 self.st -= 1;
             }
+        }
 
-            let instr = self.get_opcode();
-            self.run(instr);
+        let mut instructions = 0;
+        if self.quirks.vip_timing {
+            self.cycles += self.frame_cycles as i32;
+            while self.cycles > 0 {
+                if self.key_wait != KeyWait::Idle {
+                    self.wait_for_key(keyboard, previous);
+                    if self.key_wait != KeyWait::Idle {
+                        // the rest of the frame is spent waiting
+                        self.cycles = 0;
+                        break;
+                    }
+                }
+                let ran = self
+                    .get_opcode()
+                    .and_then(|instr| self.run(instr).map(|cycles| (instr, cycles)));
+                let (instr, cycles) = match ran {
+                    Ok(ran) => ran,
+                    Err(fault) => {
+                        // the frame is lost, so that a stopped program
+                        // ticked on does not pile up cycles
+                        self.cycles = 0;
+                        return Err(fault);
+                    }
+                };
+                self.cycles -= cycles as i32;
+                instructions += 1;
+                if self.quirks.display_wait && instr >> 12 == 0xD {
+                    self.cycles = self.cycles.min(0);
+                }
+            }
+        } else if self.key_wait == KeyWait::Idle {
+            let instr = self.get_opcode()?;
+            self.run(instr)?;
+            instructions = 1;
+        } else {
+            self.wait_for_key(keyboard, previous);
         }
 
-        State {
+        let end_of_frame = self.quirks.vip_timing || self.frame_tick + 1 >= self.tickrate;
+        self.frame_tick = if end_of_frame { 0 } else { self.frame_tick + 1 };
+        Ok(State {
             vram: &self.vram,
             vram_changed: self.vram_changed,
             beep: self.st > 0,
+            waiting_for_key: self.key_wait != KeyWait::Idle,
+            instructions,
+            end_of_frame,
+        })
+    }
+
+    /// Moves an FX0A instruction along with the keys of this tick.
+    fn wait_for_key(&mut self, keyboard: [bool; 16], previous: [bool; 16]) {
+        match self.key_wait {
+            KeyWait::Idle => {}
+            KeyWait::Press(register) => {
+                // only a key that was not already held counts as a press
+                let pressed = (0..16).find(|&key| keyboard[key] && !previous[key]);
+                if let Some(key) = pressed {
+                    if self.quirks.wait_key_press {
+                        self.vx[register] = key as u8;
+                        self.key_wait = KeyWait::Idle;
+                    } else {
+                        self.key_wait = KeyWait::Release(register, key);
+                    }
+                }
+            }
+            KeyWait::Release(register, key) => {
+                if !keyboard[key] {
+                    self.vx[register] = key as u8;
+                    self.key_wait = KeyWait::Idle;
+                }
+            }
         }
     }
 
-    fn get_opcode(&self) -> u16 {
-        BigEndian::read_u16(&self.memory[self.pc..])
+    fn get_opcode(&self) -> Result<u16, Fault> {
+        self.check_memory(self.pc, INSTR_SIZE)?;
+        Ok(BigEndian::read_u16(&self.memory[self.pc..]))
     }
 
-    pub fn run(&mut self, instr: u16) {
+    /// Makes sure the `len` bytes from `address` on are in memory.
+    fn check_memory(&self, address: usize, len: usize) -> Result<(), Fault> {
+        if address + len > self.memory.len() {
+            return Err(Fault::OutOfMemory {
+                pc: self.pc,
+                address: address.max(self.memory.len()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Runs `instr`, returning the machine cycles the VIP took for it. A
+    /// faulting instruction changes nothing.
+    pub fn run(&mut self, instr: u16) -> Result<u32, Fault> {
+        let cycles = vip_cycles(instr);
         let nimbles = (
             (instr >> 12) as usize,
             ((instr >> 8) & 0xF) as usize,
@@ -164,6 +821,9 @@ impl Interpreter {
                 self.vram_changed = true;
             }
             (0, 0, 0xE, 0xE) => {
+                if self.sp == 0 {
+                    return Err(Fault::StackUnderflow { pc: self.pc });
+                }
                 self.sp -= 1;
                 self.pc = self.stack[self.sp];
             }
@@ -175,6 +835,9 @@ impl Interpreter {
                 self.pc = (instr & 0xFFF) as usize;
             }
             (2, _, _, _) => {
+                if self.sp == self.stack.len() {
+                    return Err(Fault::StackOverflow { pc: self.pc });
+                }
                 self.stack[self.sp] = self.pc + INSTR_SIZE;
                 self.sp += 1;
                 self.pc = (instr & 0xFFF) as usize;
@@ -206,7 +869,7 @@ impl Interpreter {
             }
             (7, r, _, _) => {
                 let vx = u16::from(self.vx[r]);
-                let val = (instr & 0xFF) as u16;
+                let val = instr & 0xFF;
                 let result = vx + val;
                 self.vx[r] = result as u8;
                 self.pc += INSTR_SIZE;
@@ -217,14 +880,23 @@ impl Interpreter {
             }
             (8, r1, r2, 1) => {
                 self.vx[r1] |= self.vx[r2];
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 self.pc += INSTR_SIZE;
             }
             (8, r1, r2, 2) => {
                 self.vx[r1] &= self.vx[r2];
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 self.pc += INSTR_SIZE;
             }
             (8, r1, r2, 3) => {
                 self.vx[r1] ^= self.vx[r2];
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 self.pc += INSTR_SIZE;
             }
             (8, r1, r2, 4) => {
@@ -248,9 +920,11 @@ impl Interpreter {
                 self.vx[r1] = self.vx[r1].wrapping_sub(self.vx[r2]);
                 self.pc += INSTR_SIZE;
             }
-            (8, r1, _, 6) => {
-                self.vx[0xF] = self.vx[r1] & 0x1;
-                self.vx[r1] >>= 1;
+            (8, r1, r2, 6) => {
+                let src = if self.quirks.shift_vy { r2 } else { r1 };
+                let value = self.vx[src];
+                self.vx[r1] = value >> 1;
+                self.vx[0xF] = value & 0x1;
                 self.pc += INSTR_SIZE;
             }
             (8, r1, r2, 7) => {
@@ -263,9 +937,11 @@ impl Interpreter {
                 self.vx[r1] = self.vx[r2].wrapping_sub(self.vx[r1]);
                 self.pc += INSTR_SIZE;
             }
-            (8, r1, _, 0xE) => {
-                self.vx[0xF] = self.vx[r1] >> 7;
-                self.vx[r1] <<= 1;
+            (8, r1, r2, 0xE) => {
+                let src = if self.quirks.shift_vy { r2 } else { r1 };
+                let value = self.vx[src];
+                self.vx[r1] = value << 1;
+                self.vx[0xF] = value >> 7;
                 self.pc += INSTR_SIZE;
             }
             (9, r1, r2, 0) => {
@@ -279,23 +955,36 @@ impl Interpreter {
                 self.i = (instr & 0xFFF) as usize;
                 self.pc += INSTR_SIZE;
             }
-            (0xB, _, _, _) => {
-                self.pc = self.vx[0] as usize + (instr & 0xFFF) as usize;
+            (0xB, r, _, _) => {
+                let offset = if self.quirks.jump_vx {
+                    self.vx[r]
+                } else {
+                    self.vx[0]
+                };
+                self.pc = offset as usize + (instr & 0xFFF) as usize;
             }
             (0xC, r, _, _) => {
-                self.vx[r] = thread_rng().gen::<u8>() & (instr as u8);
+                self.vx[r] = self.next_random() & (instr as u8);
                 self.pc += INSTR_SIZE;
             }
             (0xD, r1, r2, n) => {
+                self.check_memory(self.i, n)?;
                 let sprites = (0..n)
-                    .into_iter()
                     .map(|idx| Interpreter::byte_to_bits(self.memory[self.i + idx]))
                     .collect::<Vec<_>>();
                 self.vx[0xF] = 0;
                 for (i, row) in sprites.iter().enumerate() {
-                    let y = (self.vx[r2] as usize + i) % CHIP8_HEIGHT;
+                    let y = (self.vx[r2] as usize % CHIP8_HEIGHT) + i;
+                    if self.quirks.clip_sprites && y >= CHIP8_HEIGHT {
+                        break;
+                    }
+                    let y = y % CHIP8_HEIGHT;
                     for (j, &pixel) in row.iter().enumerate() {
-                        let x = (self.vx[r1] as usize + j) % CHIP8_WIDTH;
+                        let x = (self.vx[r1] as usize % CHIP8_WIDTH) + j;
+                        if self.quirks.clip_sprites && x >= CHIP8_WIDTH {
+                            break;
+                        }
+                        let x = x % CHIP8_WIDTH;
                         if self.vram[y][x] == 1 && pixel == 1 {
                             self.vx[0xF] = 1;
                         }
@@ -306,14 +995,15 @@ impl Interpreter {
                 self.pc += INSTR_SIZE;
             }
             (0xE, r, 9, 0xE) => {
-                if self.keyboard[self.vx[r] as usize] {
+                // only the low nibble selects a key, as on the VIP
+                if self.keyboard[(self.vx[r] & 0xF) as usize] {
                     self.pc += 2 * INSTR_SIZE;
                 } else {
                     self.pc += INSTR_SIZE;
                 }
             }
             (0xE, r, 0xA, 0x1) => {
-                if !self.keyboard[self.vx[r] as usize] {
+                if !self.keyboard[(self.vx[r] & 0xF) as usize] {
                     self.pc += 2 * INSTR_SIZE;
                 } else {
                     self.pc += INSTR_SIZE;
@@ -324,8 +1014,7 @@ impl Interpreter {
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 0, 0xA) => {
-                self.keyboard_waiting = true;
-                self.keyboard_register = r as usize;
+                self.key_wait = KeyWait::Press(r);
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 1, 5) => {
@@ -337,22 +1026,28 @@ impl Interpreter {
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 1, 0xE) => {
+                // I wraps around at the end of memory, setting VF
                 let rslt = self.i + self.vx[r] as usize;
-                if rslt > 0xFFF {
+                if rslt >= self.memory.len() {
                     self.vx[0xF] = 1;
                 } else {
                     self.vx[0xF] = 0;
                 }
-                self.i = rslt & 0xFFF;
+                self.i = rslt % self.memory.len();
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 2, 9) => {
-                // font sprites are located starting with address 0x0000, each
-                // sprite is 5 bytes long
-                self.i = (self.vx[r] as usize) * 5;
+                // each font sprite is 5 bytes long
+                self.i = self.layout.font_address + (self.vx[r] as usize) * 5;
+                self.pc += INSTR_SIZE;
+            }
+            (0xF, r, 3, 0) => {
+                // each big font sprite is 10 bytes long
+                self.i = self.layout.big_font_address + (self.vx[r] as usize) * 10;
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 3, 3) => {
+                self.check_memory(self.i, 3)?;
                 let mut value = self.vx[r];
                 self.memory[self.i] = value / 100;
                 value %= 100;
@@ -362,18 +1057,291 @@ impl Interpreter {
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 5, 5) => {
+                self.check_memory(self.i, r + 1)?;
                 for idx in 0..=r {
                     self.memory[self.i + idx] = self.vx[idx];
                 }
+                if self.quirks.load_store_inc_i {
+                    self.i += r + 1;
+                }
                 self.pc += INSTR_SIZE;
             }
             (0xF, r, 6, 5) => {
+                self.check_memory(self.i, r + 1)?;
                 for idx in 0..=r {
                     self.vx[idx] = self.memory[self.i + idx];
                 }
+                if self.quirks.load_store_inc_i {
+                    self.i += r + 1;
+                }
                 self.pc += INSTR_SIZE;
             }
-            _ => panic!(format!("unsupported instruction: {:04X}", instr)),
+            _ => {
+                return Err(Fault::UnsupportedInstruction {
+                    pc: self.pc,
+                    opcode: instr,
+                })
+            }
+        }
+        Ok(cycles)
+    }
+}
+
+impl Backend for Interpreter {
+    fn tick(&mut self, keyboard: [bool; 16]) -> Result<State<'_>, Fault> {
+        Interpreter::tick(self, keyboard)
+    }
+
+    fn vram(&self) -> &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT] {
+        Interpreter::vram(self)
+    }
+
+    fn check_program(&self, program: &[u8]) -> io::Result<()> {
+        self.layout.check_program(program)
+    }
+
+    fn load(&mut self, program: &[u8]) -> io::Result<()> {
+        Interpreter::load(self, program)
+    }
+
+    /// Starts over with the options it was created with, keeping the
+    /// speed it was set to since.
+    fn reset(&mut self) {
+        let budget = self.frame_budget();
+        *self = Interpreter::with_options(self.options);
+        self.set_frame_budget(budget);
+    }
+
+    fn frame_delay(&self) -> Duration {
+        // with the VIP timing every tick is a whole 60 Hz frame
+        if self.quirks.vip_timing {
+            Duration::from_secs(1) / TIMER_RATE
+        } else {
+            Duration::from_secs(1) / TIMER_RATE / self.tickrate
+        }
+    }
+
+    fn frame_budget(&self) -> FrameBudget {
+        if self.quirks.vip_timing {
+            FrameBudget::Cycles(self.frame_cycles)
+        } else {
+            FrameBudget::Instructions(self.tickrate)
+        }
+    }
+
+    fn set_frame_budget(&mut self, budget: FrameBudget) {
+        match (budget, self.quirks.vip_timing) {
+            (FrameBudget::Instructions(n), false) => self.tickrate = n.clamp(1, MAX_TICKRATE),
+            (FrameBudget::Cycles(n), true) => {
+                self.frame_cycles = n.clamp(VIP_FETCH_CYCLES, VIP_FRAME_CYCLES * 100)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_KEYS: [bool; 16] = [false; 16];
+
+    fn interpreter(program: &[u8], quirks: Quirks) -> Interpreter {
+        let mut interpreter = Interpreter::with_options(Options {
+            quirks,
+            tickrate: 1,
+            seed: 0,
+            ..Options::default()
+        });
+        interpreter.load(program).unwrap();
+        interpreter
+    }
+
+    fn keys(held: &[usize]) -> [bool; 16] {
+        let mut keys = NO_KEYS;
+        for &key in held {
+            keys[key] = true;
+        }
+        keys
+    }
+
+    #[test]
+    fn fx0a_ignores_keys_held_before_it() {
+        // V0 = key, then loop
+        let mut interpreter = interpreter(&[0xF0, 0x0A, 0x12, 0x02], Quirks::default());
+        assert!(interpreter.tick(keys(&[5])).unwrap().waiting_for_key);
+        assert!(interpreter.tick(keys(&[5])).unwrap().waiting_for_key);
+        assert!(interpreter.tick(NO_KEYS).unwrap().waiting_for_key);
+        assert_eq!(interpreter.pc, 0x202);
+        // pressed, then waiting for the release
+        assert!(interpreter.tick(keys(&[7])).unwrap().waiting_for_key);
+        assert!(interpreter.tick(keys(&[7])).unwrap().waiting_for_key);
+        assert_eq!(interpreter.vx[0], 0);
+        assert!(!interpreter.tick(NO_KEYS).unwrap().waiting_for_key);
+        assert_eq!(interpreter.vx[0], 7);
+    }
+
+    #[test]
+    fn fx0a_completes_on_press_with_wait_key_press() {
+        let quirks = Quirks {
+            wait_key_press: true,
+            ..Quirks::default()
+        };
+        let mut interpreter = interpreter(&[0xF0, 0x0A, 0x12, 0x02], quirks);
+        assert!(interpreter.tick(NO_KEYS).unwrap().waiting_for_key);
+        assert!(!interpreter.tick(keys(&[0xC])).unwrap().waiting_for_key);
+        assert_eq!(interpreter.vx[0], 0xC);
+        assert_eq!(interpreter.pc, 0x202);
+    }
+
+    #[test]
+    fn timers_run_while_fx0a_blocks() {
+        // DT = ST = 3, V1 = key
+        let program = [0x60, 0x03, 0xF0, 0x15, 0xF0, 0x18, 0xF1, 0x0A];
+        let mut interpreter = interpreter(&program, Quirks::default());
+        for _ in 0..3 {
+            interpreter.tick(NO_KEYS).unwrap();
+        }
+        assert!(interpreter.tick(NO_KEYS).unwrap().waiting_for_key);
+        assert_eq!((interpreter.dt, interpreter.st), (1, 2));
+        let state = interpreter.tick(NO_KEYS).unwrap();
+        assert!(state.waiting_for_key && state.beep);
+        let state = interpreter.tick(NO_KEYS).unwrap();
+        assert!(state.waiting_for_key && !state.beep);
+        assert_eq!(interpreter.dt, 0);
+        assert_eq!(interpreter.pc, 0x208);
+    }
+
+    #[test]
+    fn parses_and_checks_memory_layouts() {
+        let layout = "load=0x600, size=8192".parse::<MemoryLayout>().unwrap();
+        assert_eq!(layout.load_address, 0x600);
+        assert_eq!(layout.size, 0x2000);
+        assert_eq!(layout.font_address, MemoryLayout::default().font_address);
+        assert_eq!(layout.to_string().parse::<MemoryLayout>(), Ok(layout));
+
+        assert!("size=0x800".parse::<MemoryLayout>().is_err());
+        assert!("size=0x10001".parse::<MemoryLayout>().is_err());
+        assert!("load=0x1000".parse::<MemoryLayout>().is_err());
+        assert!("font=0xFFC".parse::<MemoryLayout>().is_err());
+        assert!("load=0x600,pc=0".parse::<MemoryLayout>().is_err());
+        assert!("load=six".parse::<MemoryLayout>().is_err());
+
+        assert!(layout.check_program(&[0; 0x1A00]).is_ok());
+        assert!(layout.check_program(&[0; 0x1A01]).is_err());
+    }
+
+    #[test]
+    fn fx1e_wraps_at_the_memory_size() {
+        let mut interpreter = Interpreter::with_options(Options {
+            tickrate: 1,
+            seed: 0,
+            layout: "size=0x2000".parse().unwrap(),
+            ..Options::default()
+        });
+        // V0 = 2, I += V0, twice
+        interpreter
+            .load(&[0x60, 0x02, 0xF0, 0x1E, 0xF0, 0x1E])
+            .unwrap();
+        interpreter.i = 0x1FFD;
+        for _ in 0..2 {
+            interpreter.tick(NO_KEYS).unwrap();
+        }
+        assert_eq!(interpreter.i, 0x1FFF);
+        assert_eq!(interpreter.vx[0xF], 0);
+        interpreter.tick(NO_KEYS).unwrap();
+        assert_eq!(interpreter.i, 0x1);
+        assert_eq!(interpreter.vx[0xF], 1);
+    }
+
+    #[test]
+    fn fx29_and_fx30_point_into_the_configured_fonts() {
+        let mut interpreter = Interpreter::with_options(Options {
+            tickrate: 1,
+            seed: 0,
+            layout: "font=0x100,big_font=0x180".parse().unwrap(),
+            font: "vip".parse().unwrap(),
+            ..Options::default()
+        });
+        // V0 = 3, I = small 3, I = big 3
+        interpreter
+            .load(&[0x60, 0x03, 0xF0, 0x29, 0xF0, 0x30])
+            .unwrap();
+        interpreter.tick(NO_KEYS).unwrap();
+        interpreter.tick(NO_KEYS).unwrap();
+        assert_eq!(interpreter.i, 0x100 + 3 * 5);
+        assert_eq!(interpreter.memory[0x100 + 4 * 5], 0xA0);
+        interpreter.tick(NO_KEYS).unwrap();
+        assert_eq!(interpreter.i, 0x180 + 3 * 10);
+        assert_eq!(interpreter.memory[0x180 + 3 * 10], 0x3C);
+    }
+
+    #[test]
+    fn vip_timing_runs_a_cycle_budget_per_frame() {
+        let quirks = Quirks {
+            vip_timing: true,
+            ..Quirks::default()
+        };
+        // a jump to itself takes 52 cycles
+        let mut interpreter = interpreter(&[0x12, 0x00], quirks);
+        assert_eq!(vip_cycles(0x1200), 52);
+        let state = interpreter.tick(NO_KEYS).unwrap();
+        assert!(state.end_of_frame);
+        // the last instruction runs over into the next frame
+        assert_eq!(state.instructions, 50);
+        assert_eq!(interpreter.tick(NO_KEYS).unwrap().instructions, 49);
+
+        interpreter.set_frame_budget(FrameBudget::Cycles(52 * 10));
+        assert_eq!(interpreter.frame_budget(), FrameBudget::Cycles(520));
+        interpreter.tick(NO_KEYS).unwrap();
+        assert_eq!(interpreter.tick(NO_KEYS).unwrap().instructions, 10);
+        // instruction budgets are for the other timing
+        interpreter.set_frame_budget(FrameBudget::Instructions(3));
+        assert_eq!(interpreter.frame_budget(), FrameBudget::Cycles(520));
+    }
+
+    #[test]
+    fn display_wait_ends_the_frame_at_a_sprite() {
+        // V0 += 1, draw, loop
+        let program = [0x70, 0x01, 0xD0, 0x01, 0x12, 0x00];
+        let quirks = Quirks {
+            vip_timing: true,
+            ..Quirks::default()
+        };
+        let mut free = interpreter(&program, quirks);
+        assert!(free.tick(NO_KEYS).unwrap().instructions > 2);
+
+        let quirks = Quirks {
+            display_wait: true,
+            ..quirks
+        };
+        let mut interpreter = interpreter(&program, quirks);
+        for _ in 0..3 {
+            let state = interpreter.tick(NO_KEYS).unwrap();
+            assert!(state.vram_changed && state.end_of_frame);
+            assert_eq!(interpreter.pc, 0x204);
+        }
+        assert_eq!(interpreter.vx[0], 3);
+    }
+
+    #[test]
+    fn faults_stop_the_program_for_good() {
+        let quirks = Quirks {
+            vip_timing: true,
+            ..Quirks::default()
+        };
+        // V0 = 1, then no instruction
+        let mut interpreter = interpreter(&[0x60, 0x01, 0xF0, 0xFF], quirks);
+        let fault = Fault::UnsupportedInstruction {
+            pc: 0x202,
+            opcode: 0xF0FF,
+        };
+        for _ in 0..100 {
+            assert_eq!(interpreter.tick(NO_KEYS).err(), Some(fault));
+            assert_eq!(interpreter.pc, 0x202);
+            assert_eq!(interpreter.cycles, 0);
         }
+        assert!(interpreter.load(&[0; 0xE01]).is_err());
+        assert!(interpreter.load(&[0; 0xE00]).is_ok());
     }
 }
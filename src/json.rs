@@ -0,0 +1,353 @@
+use std::char;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Deepest nesting of arrays and objects `Json::parse` accepts, so that
+/// damaged files cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A parsed JSON value. Objects keep their keys in file order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses a whole document; errors tell the line they were found on.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            line: 1,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(parser.error(&format!("unexpected `{}` after the value", c))),
+        }
+    }
+
+    /// The value of `key` when this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref entries) => entries.iter().find(|e| e.0 == key).map(|e| &e.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match *self {
+            Json::Object(ref entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    /// Arrays and objects the parser is in.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(&format!("expected `{}`, found `{}`", expected, c))),
+            None => Err(self.error(&format!("expected `{}`, found the end", expected))),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('{') | Some('[') if self.depth == MAX_DEPTH => {
+                Err(self.error("arrays and objects nested too deeply"))
+            }
+            Some('{') => {
+                self.depth += 1;
+                let object = self.object();
+                self.depth -= 1;
+                object
+            }
+            Some('[') => {
+                self.depth += 1;
+                let array = self.array();
+                self.depth -= 1;
+                array
+            }
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(self.error(&format!("unexpected `{}`", c))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(Json::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            entries.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(entries)),
+                _ => return Err(self.error("expected `,` or `}` in object")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,` or `]` in array")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("invalid \\u escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let mut code = self.hex4()?;
+                            // characters outside the BMP come as surrogate
+                            // pairs
+                            if (0xD800..0xDC00).contains(&code) {
+                                self.expect('\\')?;
+                                self.expect('u')?;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("invalid surrogate pair"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                break;
+            }
+            text.push(c);
+            self.next();
+        }
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| self.error(&format!("invalid number `{}`", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values() {
+        let json =
+            Json::parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "d"}, "e": []} "#).unwrap();
+        assert_eq!(
+            json.get("a").and_then(Json::as_array),
+            Some(
+                &[
+                    Json::Number(1.0),
+                    Json::Number(-25.0),
+                    Json::Bool(true),
+                    Json::Null
+                ][..]
+            )
+        );
+        assert_eq!(
+            json.get("b")
+                .and_then(|b| b.get("c"))
+                .and_then(Json::as_str),
+            Some("d")
+        );
+        assert_eq!(json.get("e").and_then(Json::as_array), Some(&[][..]));
+        assert_eq!(json.get("f"), None);
+        let keys = json.as_object().unwrap().iter().map(|e| e.0.as_str());
+        assert_eq!(keys.collect::<Vec<_>>(), vec!["a", "b", "e"]);
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        let json = Json::parse(r#""q\" b\\ s\/ \n\t\r\b\f é 😀""#).unwrap();
+        assert_eq!(
+            json.as_str(),
+            Some("q\" b\\ s/ \n\t\r\u{8}\u{c} \u{e9} \u{1F600}")
+        );
+        // a lone low surrogate is no character
+        assert_eq!(
+            Json::parse(r#""\udc00""#).unwrap().as_str(),
+            Some("\u{FFFD}")
+        );
+        assert!(Json::parse(r#""\ud83dA""#).is_err());
+        assert!(Json::parse(r#""\ud83d""#).is_err());
+        assert!(Json::parse(r#""\u12G4""#).is_err());
+        assert!(Json::parse(r#""\x""#).is_err());
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(
+            Json::parse("[1,\n 2,\n 3 4]"),
+            Err("line 3: expected `,` or `]` in array".to_string())
+        );
+        assert_eq!(
+            Json::parse("{\"a\": tru}"),
+            Err("line 1: expected `e`, found `}`".to_string())
+        );
+        assert_eq!(
+            Json::parse("\"abc"),
+            Err("line 1: unterminated string".to_string())
+        );
+        assert_eq!(
+            Json::parse("{\"a\" 1}"),
+            Err("line 1: expected `:`, found `1`".to_string())
+        );
+        assert_eq!(
+            Json::parse("1-"),
+            Err("line 1: invalid number `1-`".to_string())
+        );
+        assert_eq!(Json::parse(""), Err("line 1: unexpected end".to_string()));
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        assert_eq!(
+            Json::parse("[1]\n\n]"),
+            Err("line 3: unexpected `]` after the value".to_string())
+        );
+        assert!(Json::parse("[1]\n\n").is_ok());
+    }
+
+    #[test]
+    fn limits_the_nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Json::parse(&nested(MAX_DEPTH + 1)),
+            Err("line 1: arrays and objects nested too deeply".to_string())
+        );
+        assert!(Json::parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+}
@@ -1,60 +1,580 @@
 use sdl2;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::GameControllerSubsystem;
+use std::io;
+use std::mem;
 
+use browser::MenuKey;
+use config::{Config, Entry};
+use frontend::{Hotkey, Input, Poll};
+
+/// Host keys of the default layout, indexed by CHIP-8 key. The 1234/QWER/
+/// ASDF/ZXCV block mirrors the COSMAC VIP hex keypad.
+pub const LAYOUT: [char; 16] = [
+    'x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'z', 'c', '4', 'r', 'f', 'v',
+];
+
+/// Number of game controllers that can have bindings of their own, as the
+/// `[controller1]` to `[controller4]` config sections.
+pub const MAX_PLAYERS: usize = 4;
+
+/// Stick deflection below which an axis counts as centered.
+pub const DEFAULT_DEADZONE: i16 = 8000;
+
+/// A host key, either by the symbol it types (layout dependent) or by its
+/// physical position on the keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKey {
+    Keycode(Keycode),
+    Scancode(Scancode),
+}
+
+impl HostKey {
+    /// Parses an SDL key name such as `Q`, `Up` or `Keypad 5`, optionally
+    /// prefixed with `keycode:` (the default) or `scancode:`.
+    pub fn from_name(name: &str) -> Result<HostKey, String> {
+        let name = name.trim();
+        let (by_scancode, key) = if let Some(key) = name.strip_prefix("scancode:") {
+            (true, key)
+        } else if let Some(key) = name.strip_prefix("keycode:") {
+            (false, key)
+        } else {
+            (false, name)
+        };
+        let key = key.trim();
+        let host_key = if by_scancode {
+            Scancode::from_name(key).map(HostKey::Scancode)
+        } else {
+            Keycode::from_name(key).map(HostKey::Keycode)
+        };
+        host_key.ok_or_else(|| {
+            format!(
+                "unknown {} name `{}` (expected an SDL key name such as `Q`, `Up` or `Keypad 5`)",
+                if by_scancode { "scancode" } else { "keycode" },
+                key
+            )
+        })
+    }
+
+    /// The character typed by this key, for frontends that only see text.
+    pub fn to_char(self) -> Option<char> {
+        match self {
+            HostKey::Keycode(code) => {
+                let code = code as i32;
+                if code == Keycode::Tab as i32 || (0x20..0x7F).contains(&code) {
+                    Some((code as u8 as char).to_ascii_lowercase())
+                } else {
+                    None
+                }
+            }
+            HostKey::Scancode(_) => None,
+        }
+    }
+}
+
+/// A game controller button, or one direction of an analog axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PadInput {
+    Button(Button),
+    /// The axis deflected past the deadzone, towards the positive end when
+    /// the flag is set.
+    Axis(Axis, bool),
+}
+
+impl PadInput {
+    /// Parses the names used in SDL controller mappings, such as `a`,
+    /// `dpup` or `leftshoulder`, and axes with a direction, such as
+    /// `leftx-` or `righttrigger+`.
+    pub fn from_name(name: &str) -> Result<PadInput, String> {
+        let name = name.trim().to_lowercase();
+        let button = match name.as_str() {
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "x" => Some(Button::X),
+            "y" => Some(Button::Y),
+            "back" => Some(Button::Back),
+            "guide" => Some(Button::Guide),
+            "start" => Some(Button::Start),
+            "leftstick" => Some(Button::LeftStick),
+            "rightstick" => Some(Button::RightStick),
+            "leftshoulder" => Some(Button::LeftShoulder),
+            "rightshoulder" => Some(Button::RightShoulder),
+            "dpup" => Some(Button::DPadUp),
+            "dpdown" => Some(Button::DPadDown),
+            "dpleft" => Some(Button::DPadLeft),
+            "dpright" => Some(Button::DPadRight),
+            _ => None,
+        };
+        if let Some(button) = button {
+            return Ok(PadInput::Button(button));
+        }
+
+        let (axis, positive) = if let Some(axis) = name.strip_suffix('+') {
+            (axis, true)
+        } else if let Some(axis) = name.strip_suffix('-') {
+            (axis, false)
+        } else {
+            (name.as_str(), true)
+        };
+        let axis = match axis {
+            "leftx" => Axis::LeftX,
+            "lefty" => Axis::LeftY,
+            "rightx" => Axis::RightX,
+            "righty" => Axis::RightY,
+            "lefttrigger" => Axis::TriggerLeft,
+            "righttrigger" => Axis::TriggerRight,
+            _ => {
+                return Err(format!(
+                    "unknown controller input `{}` (expected a button such as `a` or `dpup`, \
+                     or an axis such as `leftx-`)",
+                    name
+                ))
+            }
+        };
+        Ok(PadInput::Axis(axis, positive))
+    }
+
+    fn is_active(self, pad: &GameController, deadzone: i16) -> bool {
+        match self {
+            PadInput::Button(button) => pad.button(button),
+            PadInput::Axis(axis, true) => pad.axis(axis) > deadzone,
+            PadInput::Axis(axis, false) => pad.axis(axis) < -deadzone,
+        }
+    }
+}
+
+/// Controller inputs bound to each of the 16 CHIP-8 keys, for one player.
+#[derive(Clone, Debug)]
+pub struct PadBindings {
+    keys: Vec<Vec<PadInput>>,
+    deadzone: i16,
+}
+
+impl Default for PadBindings {
+    /// D-pad and left stick on the 2/4/6/8 cross most games use for
+    /// movement, A on 5 and B on 0.
+    fn default() -> Self {
+        let mut keys = vec![Vec::new(); 16];
+        keys[0x2] = vec![
+            PadInput::Button(Button::DPadUp),
+            PadInput::Axis(Axis::LeftY, false),
+        ];
+        keys[0x8] = vec![
+            PadInput::Button(Button::DPadDown),
+            PadInput::Axis(Axis::LeftY, true),
+        ];
+        keys[0x4] = vec![
+            PadInput::Button(Button::DPadLeft),
+            PadInput::Axis(Axis::LeftX, false),
+        ];
+        keys[0x6] = vec![
+            PadInput::Button(Button::DPadRight),
+            PadInput::Axis(Axis::LeftX, true),
+        ];
+        keys[0x5] = vec![PadInput::Button(Button::A)];
+        keys[0x0] = vec![PadInput::Button(Button::B)];
+        PadBindings {
+            keys,
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+}
+
+impl PadBindings {
+    /// No inputs bound, for players other than the first to start from.
+    fn empty() -> Self {
+        PadBindings {
+            keys: vec![Vec::new(); 16],
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+
+    fn apply(&self, pad: &GameController, chip8_keys: &mut [bool; 16]) {
+        for (pressed, inputs) in chip8_keys.iter_mut().zip(self.keys.iter()) {
+            if inputs
+                .iter()
+                .any(|input| input.is_active(pad, self.deadzone))
+            {
+                *pressed = true;
+            }
+        }
+    }
+}
+
+fn parse_key(config: &Config, entry: &Entry) -> io::Result<usize> {
+    match u8::from_str_radix(&entry.key, 16) {
+        Ok(key) if key < 16 => Ok(key as usize),
+        _ => Err(config.error(
+            entry,
+            &format!("`{}` is not a CHIP-8 key (expected 0-F)", entry.key),
+        )),
+    }
+}
+
+fn parse_host_keys(config: &Config, entry: &Entry) -> io::Result<Vec<HostKey>> {
+    let mut host_keys = Vec::new();
+    if !entry.value.eq_ignore_ascii_case("none") {
+        for name in entry.value.split(',') {
+            host_keys.push(HostKey::from_name(name).map_err(|e| config.error(entry, &e))?);
+        }
+    }
+    Ok(host_keys)
+}
+
+/// Host key a hotkey is bound to unless the config says otherwise.
+fn default_hotkey(hotkey: Hotkey) -> Option<Keycode> {
+    match hotkey {
+        Hotkey::NextPalette => Some(Keycode::P),
+        Hotkey::ToggleFullscreen => Some(Keycode::F11),
+        Hotkey::Screenshot => Some(Keycode::F12),
+        Hotkey::Mute => Some(Keycode::M),
+        Hotkey::Pause => Some(Keycode::Space),
+        Hotkey::FrameAdvance => Some(Keycode::N),
+        Hotkey::Faster => Some(Keycode::Equals),
+        Hotkey::Slower => Some(Keycode::Minus),
+        Hotkey::Turbo => Some(Keycode::Tab),
+        Hotkey::Menu => Some(Keycode::F1),
+        Hotkey::Redraw => None,
+    }
+}
+
+/// Host keys and controller inputs bound to each of the 16 CHIP-8 keys, and
+/// host keys bound to the emulator hotkeys.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    keys: Vec<Vec<HostKey>>,
+    hotkeys: Vec<(Hotkey, Vec<HostKey>)>,
+    /// Controller bindings per player. Players without bindings of their
+    /// own use the first player's.
+    pads: Vec<Option<PadBindings>>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut pads = vec![None; MAX_PLAYERS];
+        pads[0] = Some(PadBindings::default());
+        Bindings {
+            pads,
+            hotkeys: Hotkey::ALL
+                .iter()
+                .map(|&h| {
+                    (
+                        h,
+                        default_hotkey(h)
+                            .map(HostKey::Keycode)
+                            .into_iter()
+                            .collect(),
+                    )
+                })
+                .collect(),
+            keys: LAYOUT
+                .iter()
+                .map(|&c| {
+                    Keycode::from_name(&c.to_string())
+                        .map(HostKey::Keycode)
+                        .into_iter()
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Bindings {
+    /// Starts from the default layout and applies the `[keys]` sections of
+    /// `config`, then the per-ROM ones. Each entry maps a CHIP-8 key (hex
+    /// digit) to a comma separated list of host keys, or to `none`.
+    ///
+    /// `[hotkeys]` sections bind emulator hotkeys by name, e.g.
+    /// `next_palette = P`.
+    ///
+    /// `[controller1]` to `[controller4]` sections do the same for the
+    /// game controllers, in the order they were plugged in, and may also
+    /// set the analog `deadzone` (0-32767). The first player starts from
+    /// the default controller layout, the others from nothing bound, so
+    /// that their pads do not also drive the first player's keys.
+    pub fn from_config(config: &Config, rom_name: &str, rom_hash: &str) -> io::Result<Bindings> {
+        let mut bindings = Bindings::default();
+        for entry in config.entries("keys", rom_name, rom_hash) {
+            let key = parse_key(config, entry)?;
+            bindings.keys[key] = parse_host_keys(config, entry)?;
+        }
+
+        for entry in config.entries("hotkeys", rom_name, rom_hash) {
+            let hotkey = Hotkey::from_name(&entry.key).ok_or_else(|| {
+                let names = Hotkey::ALL.iter().map(|h| h.name()).collect::<Vec<_>>();
+                config.error(
+                    entry,
+                    &format!(
+                        "unknown hotkey `{}` (expected one of {})",
+                        entry.key,
+                        names.join(", ")
+                    ),
+                )
+            })?;
+            let host_keys = parse_host_keys(config, entry)?;
+            for binding in bindings.hotkeys.iter_mut().filter(|b| b.0 == hotkey) {
+                binding.1 = host_keys.clone();
+            }
+        }
+
+        for player in 0..MAX_PLAYERS {
+            let section = format!("controller{}", player + 1);
+            let entries = config.entries(&section, rom_name, rom_hash);
+            if entries.is_empty() {
+                continue;
+            }
+            let pad = bindings.pads[player].get_or_insert_with(PadBindings::empty);
+            for entry in entries {
+                if entry.key == "deadzone" {
+                    pad.deadzone = match entry.value.parse::<i16>() {
+                        Ok(deadzone) if deadzone >= 0 => deadzone,
+                        _ => {
+                            return Err(config.error(entry, "deadzone must be between 0 and 32767"))
+                        }
+                    };
+                    continue;
+                }
+                let key = parse_key(config, entry)?;
+                let mut inputs = Vec::new();
+                if !entry.value.eq_ignore_ascii_case("none") {
+                    for name in entry.value.split(',') {
+                        inputs
+                            .push(PadInput::from_name(name).map_err(|e| config.error(entry, &e))?);
+                    }
+                }
+                pad.keys[key] = inputs;
+            }
+        }
+        Ok(bindings)
+    }
+
+    fn pad(&self, player: usize) -> Option<&PadBindings> {
+        self.pads
+            .get(player)
+            .and_then(|pad| pad.as_ref())
+            .or_else(|| self.pads[0].as_ref())
+    }
+
+    pub fn host_keys(&self, key: usize) -> &[HostKey] {
+        &self.keys[key]
+    }
+
+    pub fn hotkey_keys(&self, hotkey: Hotkey) -> &[HostKey] {
+        self.hotkeys
+            .iter()
+            .find(|b| b.0 == hotkey)
+            .map_or(&[], |b| &b.1[..])
+    }
+
+    fn hotkeys_for(&self, keycode: Option<Keycode>, scancode: Option<Scancode>) -> Vec<Hotkey> {
+        self.hotkeys
+            .iter()
+            .filter(|binding| {
+                binding.1.iter().any(|&host| match host {
+                    HostKey::Keycode(code) => Some(code) == keycode,
+                    HostKey::Scancode(code) => Some(code) == scancode,
+                })
+            })
+            .map(|binding| binding.0)
+            .collect()
+    }
+}
+
+/// Reads the keyboard and any connected game controllers.
+///
+/// Controllers are picked up as they are plugged in; each one takes the
+/// first free player slot, so for two-player ROMs the first controller
+/// drives the `[controller1]` bindings and the second `[controller2]`.
 pub struct Keyboard {
     events: sdl2::EventPump,
+    bindings: Bindings,
+    controllers: Option<GameControllerSubsystem>,
+    pads: Vec<Option<GameController>>,
+    hotkeys: Vec<Hotkey>,
+    held_hotkeys: Vec<Hotkey>,
 }
 
 impl Keyboard {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+    pub fn new(sdl_context: &sdl2::Sdl, bindings: Bindings) -> Self {
         Keyboard {
             events: sdl_context.event_pump().unwrap(),
+            bindings,
+            // the keyboard keeps working without controller support
+            controllers: sdl_context.game_controller().ok(),
+            pads: Vec::new(),
+            hotkeys: Vec::new(),
+            held_hotkeys: Vec::new(),
         }
     }
 
-    pub fn poll(&mut self) -> Result<[bool; 16], ()> {
+    /// Replaces the bindings, e.g. with those of another ROM.
+    pub fn set_bindings(&mut self, bindings: Bindings) {
+        self.bindings = bindings;
+    }
+
+    /// Menu navigation since the last call, auto-repeats included so that
+    /// holding an arrow key scrolls.
+    pub fn menu_keys(&mut self) -> Vec<MenuKey> {
+        let mut keys = Vec::new();
         for event in self.events.poll_iter() {
-            if let Event::Quit { .. } = event {
-                return Err(());
+            let key = match event {
+                Event::Quit { .. } => MenuKey::Quit,
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                }
+                | Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                } => MenuKey::Redraw,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => match keycode {
+                    Keycode::Up => MenuKey::Up,
+                    Keycode::Down => MenuKey::Down,
+                    Keycode::PageUp => MenuKey::PageUp,
+                    Keycode::PageDown => MenuKey::PageDown,
+                    Keycode::Home => MenuKey::Home,
+                    Keycode::End => MenuKey::End,
+                    Keycode::Return | Keycode::KpEnter => MenuKey::Select,
+                    Keycode::Escape => MenuKey::Quit,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn add_controller(&mut self, joystick_index: u32) {
+        let pad = match self.controllers {
+            Some(ref controllers) if controllers.is_game_controller(joystick_index) => {
+                match controllers.open(joystick_index) {
+                    Ok(pad) => pad,
+                    Err(_) => return,
+                }
             }
+            _ => return,
+        };
+        // the same device may be reported twice at startup
+        let id = pad.instance_id();
+        if self.pads.iter().flatten().any(|p| p.instance_id() == id) {
+            return;
+        }
+        match self.pads.iter().position(|p| p.is_none()) {
+            Some(slot) => self.pads[slot] = Some(pad),
+            None => self.pads.push(Some(pad)),
         }
+    }
 
-        let keys: Vec<Keycode> = self
-            .events
-            .keyboard_state()
+    fn remove_controller(&mut self, instance_id: i32) {
+        for slot in self.pads.iter_mut() {
+            if slot.as_ref().map(|p| p.instance_id()) == Some(instance_id) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Input for Keyboard {
+    fn poll(&mut self) -> Poll {
+        let events = self.events.poll_iter().collect::<Vec<_>>();
+        for event in events {
+            match event {
+                Event::Quit { .. } => return Poll::Quit,
+                Event::ControllerDeviceAdded { which, .. } => self.add_controller(which),
+                Event::ControllerDeviceRemoved { which, .. } => self.remove_controller(which),
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                }
+                | Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                } => self.hotkeys.push(Hotkey::Redraw),
+                Event::KeyDown {
+                    keycode,
+                    scancode,
+                    repeat: false,
+                    ..
+                } => {
+                    let hotkeys = self.bindings.hotkeys_for(keycode, scancode);
+                    self.hotkeys.extend(hotkeys);
+                }
+                _ => {}
+            }
+        }
+
+        let state = self.events.keyboard_state();
+        let keys: Vec<Keycode> = state
             .pressed_scancodes()
             .filter_map(Keycode::from_scancode)
             .collect();
 
-        let mut chip8_keys = [false; 16];
+        if keys.contains(&Keycode::Escape) {
+            return Poll::Quit;
+        }
 
-        for key in keys {
-            let index = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
-                Keycode::Escape => return Err(()),
-                _ => None,
-            };
+        let is_pressed = |host: &HostKey| match *host {
+            HostKey::Keycode(code) => keys.contains(&code),
+            HostKey::Scancode(code) => state.is_scancode_pressed(code),
+        };
+
+        let mut chip8_keys = [false; 16];
+        for (i, pressed) in chip8_keys.iter_mut().enumerate() {
+            *pressed = self.bindings.host_keys(i).iter().any(&is_pressed);
+        }
+        self.held_hotkeys = self
+            .bindings
+            .hotkeys
+            .iter()
+            .filter(|binding| binding.1.iter().any(&is_pressed))
+            .map(|binding| binding.0)
+            .collect();
 
-            if let Some(i) = index {
-                chip8_keys[i] = true;
+        for (player, pad) in self.pads.iter().enumerate() {
+            if let (Some(pad), Some(bindings)) = (pad.as_ref(), self.bindings.pad(player)) {
+                bindings.apply(pad, &mut chip8_keys);
             }
         }
-        Ok(chip8_keys)
+        Poll::Keys(chip8_keys)
+    }
+
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        mem::take(&mut self.hotkeys)
+    }
+
+    fn held_hotkeys(&self) -> Vec<Hotkey> {
+        self.held_hotkeys.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_players_start_from_nothing_bound() {
+        let text = "[controller1]\n1 = x\n[controller2]\n5 = a\ndeadzone = 100\n";
+        let config = Config::parse("test.ini", text).unwrap();
+        let bindings = Bindings::from_config(&config, "", "").unwrap();
+
+        let first = bindings.pad(0).unwrap();
+        assert_eq!(first.keys[0x1], vec![PadInput::Button(Button::X)]);
+        assert_eq!(first.keys[0x2], PadBindings::default().keys[0x2]);
+
+        let second = bindings.pad(1).unwrap();
+        assert_eq!(second.keys[0x5], vec![PadInput::Button(Button::A)]);
+        assert_eq!(second.deadzone, 100);
+        let bound = second.keys.iter().filter(|inputs| !inputs.is_empty());
+        assert_eq!(bound.count(), 1);
+
+        // unconfigured players share the first one's bindings
+        assert_eq!(bindings.pad(2).unwrap().keys, first.keys);
     }
 }
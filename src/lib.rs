@@ -0,0 +1,19 @@
+//! The CHIP-8 cores and what they need, without SDL, so that they can be
+//! built, fuzzed and tested on their own. The `chip8` binary puts the frontends
+//! around them.
+
+extern crate byteorder;
+extern crate rand;
+
+pub mod cdp1802;
+pub mod config;
+pub mod diff;
+pub mod disassembler;
+pub mod font;
+pub mod gdb;
+pub mod interpreter;
+pub mod vip;
+
+pub const CHIP8_WIDTH: usize = 64;
+pub const CHIP8_HEIGHT: usize = 32;
+pub const CHIP8_RAM: usize = 4096;
@@ -1,56 +1,581 @@
 extern crate byteorder;
-extern crate rand;
+extern crate chip8;
+extern crate libc;
 extern crate sdl2;
+extern crate sha1_smol;
 
+use std::env;
 use std::fs::metadata;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::net::TcpListener;
+use std::path::Path;
 
-use std::thread;
 use std::time::Duration;
 
+use chip8::{config, diff, disassembler, font, gdb, interpreter, vip};
+use chip8::{CHIP8_HEIGHT, CHIP8_WIDTH};
+
 pub mod audio;
-pub mod disassembler;
-pub mod interpreter;
+pub mod browser;
+pub mod capture;
+pub mod cli;
+pub mod database;
+pub mod filter;
+pub mod frontend;
+pub mod headless;
+pub mod json;
 pub mod keyboard;
+pub mod movie;
+pub mod osd;
+pub mod palette;
 pub mod screen;
+pub mod terminal;
+pub mod watch;
+pub mod wav;
 
-use audio::Audio;
-use interpreter::Interpreter;
-use keyboard::Keyboard;
-use screen::Screen;
-
-const CHIP8_WIDTH: usize = 64;
-const CHIP8_HEIGHT: usize = 32;
-const CHIP8_RAM: usize = 4096;
+use audio::{Audio, Tone};
+use browser::Browser;
+use capture::{Capture, ImageOptions, Screenshots};
+use cli::Args;
+use config::Config;
+use database::{Database, RomInfo};
+use disassembler::Listing;
+use filter::Filter;
+use font::Font;
+use frontend::{Beeper, Display, Emulator, Input, Playback};
+use headless::{FrameLimit, Headless, Idle, Silence};
+use interpreter::{Backend, Interpreter, MemoryLayout, Options};
+use keyboard::{Bindings, Keyboard};
+use movie::{Machine, Movie};
+use palette::Palette;
+use screen::{Screen, WindowOptions};
+use terminal::{Bell, TerminalDisplay, TerminalInput};
+use vip::Vip;
+use watch::RomWatcher;
+use wav::WavWriter;
 
-pub fn main() -> std::io::Result<()> {
-    let sleep_duration = Duration::from_millis(2);
-    let size = metadata("pong1player.ch8")?.len();
-    let mut file = File::open("pong1player.ch8")?;
+fn read_rom<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let size = metadata(&path)?.len();
+    let mut file = File::open(path)?;
     let mut program = Vec::with_capacity(size as usize);
     let _ = file.read_to_end(&mut program)?;
+    Ok(program)
+}
+
+/// Settings looked up in the config for a ROM, unless given on the command
+/// line.
+struct RomSettings {
+    bindings: Bindings,
+    palette: Palette,
+    filter: Filter,
+    tone: Tone,
+    layout: MemoryLayout,
+    font: Font,
+}
+
+impl RomSettings {
+    fn load(
+        config: &Config,
+        args: &Args,
+        rom_name: &str,
+        rom_hash: &str,
+        info: Option<&RomInfo>,
+    ) -> io::Result<Self> {
+        let palette = match args.palette {
+            Some(ref palette) => palette.clone(),
+            None => Palette::from_config(config, rom_name, rom_hash)?
+                .or_else(|| info.and_then(|info| info.colors.clone()))
+                .unwrap_or_default(),
+        };
+        let filter = match args.filter {
+            Some(filter) => filter,
+            None => Filter::from_config(config, rom_name, rom_hash)?.unwrap_or_default(),
+        };
+        Ok(RomSettings {
+            bindings: Bindings::from_config(config, rom_name, rom_hash)?,
+            palette,
+            filter,
+            tone: Tone::from_config(config, rom_name, rom_hash)?,
+            layout: MemoryLayout::from_config(config, rom_name, rom_hash)?,
+            font: match args.font {
+                Some(font) => font,
+                None => Font::from_config(config, rom_name, rom_hash)?.unwrap_or_default(),
+            },
+        })
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Screenshots of a ROM are named after it.
+fn screenshot_prefix(rom_name: &str) -> String {
+    Path::new(rom_name)
+        .file_stem()
+        .map_or("chip8".to_string(), |stem| {
+            stem.to_string_lossy().into_owned()
+        })
+}
+
+/// Interpreter options from the command line, or from the database entry
+/// of the ROM.
+fn options(args: &Args, info: Option<&RomInfo>, layout: MemoryLayout, font: Font) -> Options {
+    let mut options = Options {
+        layout,
+        font,
+        ..Options::default()
+    };
+    if let Some(seed) = args.seed {
+        options.seed = seed;
+    }
+    if let Some(tickrate) = args
+        .tickrate
+        .or_else(|| info.and_then(|info| info.tickrate))
+    {
+        options.tickrate = tickrate;
+    }
+    if let Some(quirks) = args.quirks.or_else(|| info.map(|info| info.quirks)) {
+        options.quirks = quirks;
+    }
+    options
+}
+
+/// The CHIP-8 implementation to run `program` on: the built-in
+/// interpreter, or with `--vip` the original one on an emulated VIP.
+fn backend(args: &Args, options: Options, program: &[u8]) -> io::Result<Box<dyn Backend>> {
+    let mut backend: Box<dyn Backend> = match (&args.vip, &args.vip_monitor) {
+        (Some(interpreter), Some(monitor)) => {
+            Box::new(Vip::new(&read_rom(interpreter)?, &read_rom(monitor)?)?)
+        }
+        _ => Box::new(Interpreter::with_options(options)),
+    };
+    backend.load(program)?;
+    Ok(backend)
+}
+
+/// What `backend` runs programs on, for movies to refuse playing on
+/// another machine than they were recorded on.
+fn machine(args: &Args) -> io::Result<Machine> {
+    match (&args.vip, &args.vip_monitor) {
+        (Some(interpreter), Some(monitor)) => {
+            Ok(Machine::vip(&read_rom(interpreter)?, &read_rom(monitor)?))
+        }
+        _ => Ok(Machine::Interpreter),
+    }
+}
+
+/// Tells what the database knows about the ROM being started.
+fn report(info: &RomInfo) {
+    eprintln!("{}", info);
+    if let Some(tickrate) = info.tickrate {
+        eprintln!("tickrate: {} instructions per frame", tickrate);
+    }
+    if !info.keys.is_empty() {
+        eprintln!("keys: {}", browser::key_hints(info));
+    }
+}
+
+fn title(rom_name: &str, info: Option<&RomInfo>) -> String {
+    format!(
+        "CHIP-8 - {}",
+        info.map_or(rom_name, |info| info.title.as_str())
+    )
+}
+
+/// What every runner sets up the same way around the emulator.
+struct Session {
+    recording: Option<Movie>,
+    filter: Filter,
+    image: ImageOptions,
+    screenshot_prefix: String,
+    screenshot: Option<String>,
+    capture: Option<String>,
+    watch: Option<RomWatcher>,
+}
+
+impl Session {
+    fn start<D: Display, I: Input, A: Beeper>(
+        &mut self,
+        emulator: &mut Emulator<D, I, A>,
+    ) -> io::Result<()> {
+        emulator.set_filter(self.filter);
+        if let Some(movie) = self.recording.take() {
+            emulator.record(movie);
+        }
+        if let Some(watcher) = self.watch.take() {
+            emulator.watch(watcher);
+        }
+        if let Some(ref path) = self.capture {
+            emulator.capture(Capture::create(path, self.image.clone())?);
+        }
+        emulator.set_screenshots(Screenshots {
+            prefix: self.screenshot_prefix.clone(),
+            options: self.image.clone(),
+        });
+        Ok(())
+    }
+
+    /// Saves the screenshot and the capture, returning the recorded movie.
+    fn finish<D: Display, I: Input, A: Beeper>(
+        &self,
+        emulator: &mut Emulator<D, I, A>,
+    ) -> io::Result<Option<Movie>> {
+        emulator.finish()?;
+        if let Some(ref path) = self.screenshot {
+            capture::save_png(path, emulator.frame(), &self.image)?;
+        }
+        Ok(emulator.take_recording())
+    }
+}
+
+fn run_sdl(
+    interpreter: Box<dyn Backend>,
+    playback: Option<Movie>,
+    bindings: Bindings,
+    palette: Palette,
+    window: WindowOptions,
+    tone: Tone,
+    session: &mut Session,
+) -> io::Result<Option<Movie>> {
     let sdl_context = sdl2::init().unwrap();
-    let mut keyboard = Keyboard::new(&sdl_context);
-    let mut screen = Screen::new(&sdl_context);
-    let audio = Audio::new(&sdl_context);
-    let mut interpreter = Interpreter::default();
-    interpreter.load(&program);
+    let keyboard = Keyboard::new(&sdl_context, bindings);
+    let screen = Screen::new(&sdl_context, palette, window);
+    let audio = Audio::new(&sdl_context, tone);
+
+    let playing = playback.is_some();
+    let input: Box<dyn Input> = match playback {
+        Some(movie) => Box::new(Playback::new(movie, Some(keyboard))),
+        None => Box::new(keyboard),
+    };
+    let mut emulator = Emulator::new(interpreter, screen, input, audio);
+    if playing {
+        emulator.lock_speed();
+    }
+    session.start(&mut emulator)?;
+    emulator.run();
+    session.finish(&mut emulator)
+}
 
-    while let Ok(keys) = keyboard.poll() {
-        let output = interpreter.tick(keys);
+fn run_terminal(
+    interpreter: Box<dyn Backend>,
+    playback: Option<Movie>,
+    bindings: &Bindings,
+    palette: Palette,
+    args: &Args,
+    session: &mut Session,
+) -> io::Result<Option<Movie>> {
+    let keys = TerminalInput::new(Duration::from_millis(args.key_hold), bindings)?;
+    let playing = playback.is_some();
+    let input: Box<dyn Input> = match playback {
+        Some(movie) => Box::new(Playback::new(movie, Some(keys))),
+        None => Box::new(keys),
+    };
+    let display = TerminalDisplay::new(args.glyphs, palette);
+    let mut emulator = Emulator::new(interpreter, display, input, Bell::default());
+    if playing {
+        emulator.lock_speed();
+    }
+    session.start(&mut emulator)?;
+    emulator.run();
+    session.finish(&mut emulator)
+}
 
-        if output.vram_changed {
-            screen.draw(output.vram);
+fn run_headless(
+    interpreter: Box<dyn Backend>,
+    playback: Option<Movie>,
+    frames: Option<u64>,
+    wav: Option<(&str, Tone)>,
+    session: &mut Session,
+) -> io::Result<Option<Movie>> {
+    let input: Box<dyn Input> = match (playback, frames) {
+        (Some(movie), Some(n)) => Box::new(FrameLimit::new(Playback::<Idle>::new(movie, None), n)),
+        (Some(movie), None) => Box::new(Playback::<Idle>::new(movie, None)),
+        (None, Some(n)) => Box::new(FrameLimit::new(Idle, n)),
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--headless needs either --play or --frames",
+            ))
         }
+    };
 
-        if output.beep {
-            audio.start_beep();
-        } else {
-            audio.stop_beep();
+    let beeper: Box<dyn Beeper> = match wav {
+        Some((path, tone)) => Box::new(WavWriter::create(path, tone)?),
+        None => Box::new(Silence),
+    };
+    let mut emulator = Emulator::new(interpreter, Headless::default(), input, beeper);
+    emulator.set_frame_delay(Duration::from_millis(0));
+    session.start(&mut emulator)?;
+    let count = emulator.run();
+
+    let pixels = emulator
+        .display()
+        .framebuffer()
+        .iter()
+        .flat_map(|row| row.iter().cloned())
+        .collect::<Vec<_>>();
+    println!("frames: {}", count);
+    println!("vram sha1: {}", movie::rom_hash(&pixels));
+    session.finish(&mut emulator)
+}
+
+/// Lets the player pick ROMs from the `args.rom` directory, going back to
+/// the list whenever they press the menu hotkey.
+fn browse(args: &Args, config: &Config, database: &Database) -> io::Result<()> {
+    if args.headless || args.terminal {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a ROM directory can only be browsed in the window",
+        ));
+    }
+    if args.record.is_some()
+        || args.play.is_some()
+        || args.watch
+        || args.screenshot.is_some()
+        || args.capture.is_some()
+        || args.wav.is_some()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--record, --play, --watch, --screenshot, --capture and --wav need a single ROM",
+        ));
+    }
+    let roms = browser::list_roms(&args.rom, database)?;
+    if roms.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no ROMs in {}", args.rom),
+        ));
+    }
+    let mut browser = Browser::new(roms);
+
+    let defaults = RomSettings::load(config, args, "", "", None)?;
+    let sdl_context = sdl2::init().unwrap();
+    let mut keyboard = Keyboard::new(&sdl_context, defaults.bindings);
+    let window = WindowOptions {
+        title: "CHIP-8".to_string(),
+        scale: args.scale,
+        integer_scale: args.integer_scale,
+        fullscreen: args.fullscreen,
+        show_fps: args.show_fps,
+    };
+    let mut screen = Screen::new(&sdl_context, defaults.palette, window);
+    let mut audio = Audio::new(&sdl_context, defaults.tone);
+
+    while let Some(rom) = browser.choose(&mut keyboard, &mut screen) {
+        let program = match read_rom(&rom.path) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("cannot read {}: {}", rom.path.display(), e);
+                continue;
+            }
+        };
+        let rom_hash = movie::rom_hash(&program);
+        let info = database.lookup(&rom_hash);
+        if let Some(info) = info {
+            report(info);
         }
+        let settings = RomSettings::load(config, args, &rom.name, &rom_hash, info)?;
+        if let Err(e) = settings.layout.check_program(&program) {
+            eprintln!("cannot load {}: {}", rom.path.display(), e);
+            continue;
+        }
+        keyboard.set_bindings(settings.bindings);
+        screen.set_palette(settings.palette.clone());
+        screen.set_title(&title(&rom.name, info));
+        audio.set_tone(settings.tone);
+
+        let options = options(args, info, settings.layout, settings.font);
+        let interpreter = match backend(args, options, &program) {
+            Ok(interpreter) => interpreter,
+            Err(e) => {
+                eprintln!("cannot load {}: {}", rom.path.display(), e);
+                continue;
+            }
+        };
+        let mut session = Session {
+            recording: None,
+            filter: settings.filter,
+            image: ImageOptions {
+                palette: settings.palette,
+                scale: args.capture_scale,
+            },
+            screenshot_prefix: screenshot_prefix(&rom.name),
+            screenshot: None,
+            capture: None,
+            watch: None,
+        };
+
+        let mut emulator = Emulator::new(interpreter, screen, keyboard, audio);
+        emulator.enable_menu();
+        session.start(&mut emulator)?;
+        emulator.run();
+        session.finish(&mut emulator)?;
+        let back = emulator.left_for_menu();
+        let frontends = emulator.into_frontends();
+        screen = frontends.0;
+        keyboard = frontends.1;
+        audio = frontends.2;
+        audio.set(false, None);
+        if !back {
+            break;
+        }
+        screen.set_title("CHIP-8");
+    }
+    Ok(())
+}
+
+pub fn main() -> io::Result<()> {
+    let args = Args::parse(env::args().skip(1))?;
+    let config = match args.config {
+        Some(ref path) => Config::load(path)?,
+        None if Path::new(config::DEFAULT_PATH).exists() => Config::load(config::DEFAULT_PATH)?,
+        None => Config::default(),
+    };
+    let database = match args.database {
+        Some(ref path) => Database::load(path)?,
+        None if Path::new(database::DEFAULT_PATH).exists() => {
+            Database::load(database::DEFAULT_PATH)?
+        }
+        None => Database::default(),
+    };
+    if Path::new(&args.rom).is_dir() {
+        return browse(&args, &config, &database);
+    }
+
+    let program = read_rom(&args.rom)?;
+    let rom_name = file_name(Path::new(&args.rom));
+    let rom_hash = movie::rom_hash(&program);
+    let info = database.lookup(&rom_hash);
+    if let Some(info) = info {
+        report(info);
+    }
+    let RomSettings {
+        bindings,
+        palette,
+        filter,
+        tone,
+        layout,
+        font,
+    } = RomSettings::load(&config, &args, &rom_name, &rom_hash, info)?;
+
+    let machine = machine(&args)?;
+    let playback = match args.play {
+        Some(ref path) => {
+            let movie = Movie::load(path)?;
+            movie.verify(&program, &machine)?;
+            Some(movie)
+        }
+        None => None,
+    };
+
+    let options = match playback {
+        Some(ref movie) => movie.options,
+        None => options(&args, info, layout, font),
+    };
+    options.layout.check_program(&program)?;
+    if args.disassemble {
+        print!("{}", Listing::new(&program, options.layout));
+        return Ok(());
+    }
+    if let Some(quirks) = args.diff {
+        let frames = match playback {
+            Some(ref movie) => movie.frames.clone(),
+            None => vec![[false; 16]; args.frames.unwrap_or(0) as usize],
+        };
+        let other = Options { quirks, ..options };
+        if machine != Machine::Interpreter {
+            // the VIP shares no state with the interpreter but what the
+            // player sees and hears, so the runs are compared per frame
+            let mut interpreter = Interpreter::with_options(other);
+            interpreter.load(&program)?;
+            let mut vip = backend(&args, options, &program)?;
+            println!("a: quirks {}\nb: {}", other.quirks, machine);
+            match diff::compare_frames(&mut interpreter, &mut *vip, &frames) {
+                Ok(Some(divergence)) => print!("{}", divergence),
+                Ok(None) => println!("the runs agree for all {} frames", frames.len()),
+                Err(fault) => println!("the runs agree until both stop: {}", fault),
+            }
+            return Ok(());
+        }
+        println!("a: quirks {}\nb: quirks {}", options.quirks, other.quirks);
+        match diff::compare(&program, options, other, &frames)? {
+            Ok(Some(divergence)) => print!("{}", divergence),
+            Ok(None) => println!("the runs agree for all {} ticks", frames.len()),
+            Err(fault) => println!("the runs agree until both stop: {}", fault),
+        }
+        return Ok(());
+    }
+
+    if let Some(port) = args.gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept()?;
+        let mut interpreter = Interpreter::with_options(options);
+        interpreter.load(&program)?;
+        return gdb::Stub::new(interpreter, stream)?.serve();
+    }
+
+    let interpreter = backend(&args, options, &program)?;
+
+    let mut session = Session {
+        recording: args
+            .record
+            .as_ref()
+            .map(|_| Movie::new(&program, machine.clone(), options)),
+        filter,
+        image: ImageOptions {
+            palette: palette.clone(),
+            scale: args.capture_scale,
+        },
+        screenshot_prefix: screenshot_prefix(&rom_name),
+        screenshot: args.screenshot.clone(),
+        capture: args.capture.clone(),
+        watch: if args.watch {
+            Some(RomWatcher::new(&args.rom, args.keep_state, options.layout))
+        } else {
+            None
+        },
+    };
+
+    let recording = if args.headless {
+        let wav = args.wav.as_ref().map(|path| (path.as_str(), tone));
+        run_headless(interpreter, playback, args.frames, wav, &mut session)?
+    } else if args.terminal {
+        run_terminal(
+            interpreter,
+            playback,
+            &bindings,
+            palette,
+            &args,
+            &mut session,
+        )?
+    } else {
+        let window = WindowOptions {
+            title: title(&rom_name, info),
+            scale: args.scale,
+            integer_scale: args.integer_scale,
+            fullscreen: args.fullscreen,
+            show_fps: args.show_fps,
+        };
+        run_sdl(
+            interpreter,
+            playback,
+            bindings,
+            palette,
+            window,
+            tone,
+            &mut session,
+        )?
+    };
 
-        thread::sleep(sleep_duration);
+    if let (Some(path), Some(movie)) = (args.record, recording) {
+        movie.save(path)?;
     }
     Ok(())
 }
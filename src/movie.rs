@@ -0,0 +1,412 @@
+use sha1_smol::Sha1;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use font::Font;
+use interpreter::{MemoryLayout, Options, Quirks};
+
+const MAGIC: &str = "CHIP8-MOVIE 1";
+
+/// Most frames a movie may hold, so that a damaged header cannot make
+/// loading run out of memory.
+const MAX_FRAMES: usize = 300_000_000;
+
+/// Hex encoded SHA-1 of a ROM image, used to tie a movie to the program it
+/// was recorded against.
+pub fn rom_hash(program: &[u8]) -> String {
+    Sha1::from(program).digest().to_string()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The value of a `name value` header line.
+fn header_value(line: &str, name: &str) -> io::Result<String> {
+    let mut parts = line.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if key == name => Ok(value.trim().to_string()),
+        _ => Err(invalid(format!(
+            "expected `{}` header, got `{}`",
+            name, line
+        ))),
+    }
+}
+
+/// The CHIP-8 implementation a movie was recorded on, as the same input
+/// makes for a different run on each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Machine {
+    /// The built-in interpreter, set up by the `Options` of the movie.
+    Interpreter,
+    /// An emulated VIP, identified by the hashes of its CHIP-8 interpreter
+    /// and monitor ROM. The `Options` of the movie do not apply to it.
+    Vip {
+        interpreter: String,
+        monitor: String,
+    },
+}
+
+impl Machine {
+    pub fn vip(interpreter: &[u8], monitor: &[u8]) -> Self {
+        Machine::Vip {
+            interpreter: rom_hash(interpreter),
+            monitor: rom_hash(monitor),
+        }
+    }
+}
+
+/// Formats the machine as `interpreter` or `vip INTERPRETER MONITOR`, the
+/// format `FromStr` reads back.
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Machine::Interpreter => write!(f, "interpreter"),
+            Machine::Vip {
+                ref interpreter,
+                ref monitor,
+            } => write!(f, "vip {} {}", interpreter, monitor),
+        }
+    }
+}
+
+impl FromStr for Machine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        match parts[..] {
+            ["interpreter"] => Ok(Machine::Interpreter),
+            ["vip", interpreter, monitor] => Ok(Machine::Vip {
+                interpreter: interpreter.to_string(),
+                monitor: monitor.to_string(),
+            }),
+            _ => Err(format!("invalid machine `{}`", s.trim())),
+        }
+    }
+}
+
+fn keys_to_mask(keys: &[bool; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .fold(0, |mask, (idx, &k)| if k { mask | 1 << idx } else { mask })
+}
+
+fn mask_to_keys(mask: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (idx, k) in keys.iter_mut().enumerate() {
+        *k = mask & (1 << idx) != 0;
+    }
+    keys
+}
+
+/// The key state passed to `Interpreter::tick` on every tick, together with
+/// everything else that makes a run reproducible.
+///
+/// Movies are stored as text: a small header followed by one line per run of
+/// identical frames, holding the key bitmask in hex (bit N set when key N is
+/// down) and an optional `*count` repeat, e.g. `0020*45`.
+#[derive(Clone, Debug)]
+pub struct Movie {
+    pub rom_hash: String,
+    pub machine: Machine,
+    pub options: Options,
+    pub frames: Vec<[bool; 16]>,
+}
+
+impl Movie {
+    pub fn new(program: &[u8], machine: Machine, options: Options) -> Self {
+        Movie {
+            rom_hash: rom_hash(program),
+            machine,
+            options,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, keys: [bool; 16]) {
+        self.frames.push(keys);
+    }
+
+    /// Checks that the movie was recorded against `program` on `machine`.
+    pub fn verify(&self, program: &[u8], machine: &Machine) -> io::Result<()> {
+        let hash = rom_hash(program);
+        if hash != self.rom_hash {
+            return Err(invalid(format!(
+                "movie was recorded for ROM {}, but the loaded ROM is {}",
+                self.rom_hash, hash
+            )));
+        }
+        if *machine != self.machine {
+            return Err(invalid(format!(
+                "movie was recorded on {}, but the ROM runs on {}",
+                self.machine, machine
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "rom {}", self.rom_hash)?;
+        // left out for the built-in interpreter, which is all older versions
+        // can run
+        if self.machine != Machine::Interpreter {
+            writeln!(out, "machine {}", self.machine)?;
+        }
+        writeln!(out, "seed {}", self.options.seed)?;
+        writeln!(out, "quirks {}", self.options.quirks)?;
+        // left out at one instruction per frame, which is what movies
+        // recorded before the tickrate could be set ran at
+        if self.options.tickrate != 1 {
+            writeln!(out, "tickrate {}", self.options.tickrate)?;
+        }
+        // left out when it is the default, so that older versions can still
+        // play the movie
+        if self.options.layout != MemoryLayout::default() {
+            writeln!(out, "layout {}", self.options.layout)?;
+        }
+        if self.options.font != Font::default() {
+            writeln!(out, "font {}", self.options.font)?;
+        }
+        writeln!(out, "frames {}", self.frames.len())?;
+
+        let mut frames = self.frames.iter().map(keys_to_mask).peekable();
+        while let Some(mask) = frames.next() {
+            let mut count = 1;
+            while frames.peek() == Some(&mask) {
+                frames.next();
+                count += 1;
+            }
+            if count == 1 {
+                writeln!(out, "{:04X}", mask)?;
+            } else {
+                writeln!(out, "{:04X}*{}", mask, count)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Movie::read(BufReader::new(File::open(path)?))
+    }
+
+    fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        let mut next_line = || -> io::Result<String> {
+            lines
+                .next()
+                .unwrap_or_else(|| Err(invalid("unexpected end of movie".to_string())))
+        };
+
+        if next_line()?.trim() != MAGIC {
+            return Err(invalid("not a CHIP-8 movie file".to_string()));
+        }
+
+        let rom_hash = header_value(&next_line()?, "rom")?;
+        let mut line = next_line()?;
+        let machine = if line.starts_with("machine ") {
+            let machine = header_value(&line, "machine")?
+                .parse::<Machine>()
+                .map_err(invalid)?;
+            line = next_line()?;
+            machine
+        } else {
+            Machine::Interpreter
+        };
+        let seed = header_value(&line, "seed")?
+            .parse::<u64>()
+            .map_err(|e| invalid(format!("invalid seed: {}", e)))?;
+        let quirks = header_value(&next_line()?, "quirks")?
+            .parse::<Quirks>()
+            .map_err(invalid)?;
+        line = next_line()?;
+        let tickrate = if line.starts_with("tickrate ") {
+            let tickrate = header_value(&line, "tickrate")?
+                .parse::<u32>()
+                .map_err(|e| invalid(format!("invalid tickrate: {}", e)))?;
+            line = next_line()?;
+            tickrate
+        } else {
+            1
+        };
+        let layout = if line.starts_with("layout ") {
+            let layout = header_value(&line, "layout")?
+                .parse::<MemoryLayout>()
+                .map_err(invalid)?;
+            line = next_line()?;
+            layout
+        } else {
+            MemoryLayout::default()
+        };
+        let font = if line.starts_with("font ") {
+            let font = header_value(&line, "font")?
+                .parse::<Font>()
+                .map_err(invalid)?;
+            line = next_line()?;
+            font
+        } else {
+            Font::default()
+        };
+        let count = header_value(&line, "frames")?
+            .parse::<usize>()
+            .map_err(|e| invalid(format!("invalid frame count: {}", e)))?;
+
+        if count > MAX_FRAMES {
+            return Err(invalid(format!(
+                "movie header announces {} frames, more than the {} supported",
+                count, MAX_FRAMES
+            )));
+        }
+
+        let mut frames = Vec::new();
+        for line in lines {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid_frame = || invalid(format!("invalid frame: `{}`", line));
+            let mut parts = line.splitn(2, '*');
+            let mask =
+                u16::from_str_radix(parts.next().unwrap_or(""), 16).map_err(|_| invalid_frame())?;
+            let repeat = match parts.next() {
+                Some(n) => n.parse::<usize>().map_err(|_| invalid_frame())?,
+                None => 1,
+            };
+            if repeat == 0 {
+                return Err(invalid_frame());
+            }
+            match frames.len().checked_add(repeat) {
+                Some(total) if total <= count => {
+                    frames.extend((0..repeat).map(|_| mask_to_keys(mask)))
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "movie header announces {} frames, found more",
+                        count
+                    )))
+                }
+            }
+        }
+
+        if frames.len() != count {
+            return Err(invalid(format!(
+                "movie header announces {} frames, found {}",
+                count,
+                frames.len()
+            )));
+        }
+
+        Ok(Movie {
+            rom_hash,
+            machine,
+            options: Options {
+                quirks,
+                tickrate,
+                seed,
+                layout,
+                font,
+            },
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> io::Result<Movie> {
+        Movie::read(text.as_bytes())
+    }
+
+    fn keys(mask: u16) -> [bool; 16] {
+        mask_to_keys(mask)
+    }
+
+    #[test]
+    fn saves_and_loads_a_movie() {
+        let mut movie = Movie::new(
+            &[0x12, 0x00],
+            Machine::vip(&[1], &[2]),
+            Options {
+                tickrate: 8,
+                seed: 42,
+                ..Options::default()
+            },
+        );
+        for &mask in &[0, 0, 0x0020, 0x8001, 0x8001, 0x8001, 0] {
+            movie.record(keys(mask));
+        }
+        let mut text = Vec::new();
+        movie.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.ends_with("frames 7\n0000*2\n0020\n8001*3\n0000\n"));
+
+        let loaded = read(&text).unwrap();
+        assert_eq!(loaded.rom_hash, movie.rom_hash);
+        assert_eq!(loaded.machine, movie.machine);
+        assert_eq!(loaded.options.seed, 42);
+        assert_eq!(loaded.options.tickrate, 8);
+        assert_eq!(loaded.options.quirks, movie.options.quirks);
+        assert_eq!(loaded.frames, movie.frames);
+        assert!(loaded.verify(&[0x12, 0x00], &movie.machine).is_ok());
+        assert!(loaded.verify(&[0x12, 0x02], &movie.machine).is_err());
+        assert!(loaded.verify(&[0x12, 0x00], &Machine::Interpreter).is_err());
+    }
+
+    #[test]
+    fn reads_older_headers_with_defaults() {
+        let movie = read("CHIP8-MOVIE 1\nrom ab\nseed 1\nquirks none\nframes 1\n0001\n").unwrap();
+        assert_eq!(movie.machine, Machine::Interpreter);
+        assert_eq!(movie.options.tickrate, 1);
+        assert_eq!(movie.options.layout, MemoryLayout::default());
+        assert_eq!(movie.frames, vec![keys(1)]);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let header = "CHIP8-MOVIE 1\nrom ab\nseed 1\nquirks none\n";
+        assert!(read("CHIP8-MOVIE 2\n").is_err());
+        assert!(read("CHIP8-MOVIE 1\nrom ab\n").is_err());
+        assert!(read("CHIP8-MOVIE 1\nrom ab\nseed x\n").is_err());
+        assert!(read(&format!("{}tickrate -1\nframes 0\n", header)).is_err());
+        assert!(read(&format!("{}frames\n", header)).is_err());
+        assert!(read(&format!("{}frames 18446744073709551615\n", header)).is_err());
+        assert!(read(&format!("{}frames 0\n", header)).is_ok());
+    }
+
+    #[test]
+    fn checks_run_lengths_against_the_header() {
+        let header = "CHIP8-MOVIE 1\nrom ab\nseed 1\nquirks none\n";
+        let movie = read(&format!("{}frames 5\n0001*2\n\n0002*3\n", header)).unwrap();
+        assert_eq!(
+            movie.frames,
+            vec![keys(1), keys(1), keys(2), keys(2), keys(2)]
+        );
+        for frames in &[
+            "frames 2\n0001*3\n",
+            "frames 3\n0001*2\n",
+            "frames 1\n0001*0\n0001\n",
+            "frames 2\n0001\n0001*18446744073709551615\n",
+            "frames 1\n0001*x\n",
+            "frames 1\n10000\n",
+        ] {
+            assert!(
+                read(&format!("{}{}", header, frames)).is_err(),
+                "{}",
+                frames
+            );
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+use interpreter::FONTS;
+
+/// Glyphs are 4x5 pixels, like the CHIP-8 font, with a blank column
+/// between them.
+pub const GLYPH_WIDTH: u32 = 4;
+pub const GLYPH_HEIGHT: u32 = 5;
+pub const ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+/// How long a message stays on screen.
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
+
+/// The letters and symbols missing from the CHIP-8 hex font, in the same
+/// format: one byte per row with the pixels in the high nibble.
+const EXTRA_GLYPHS: [(char, [u8; 5]); 36] = [
+    ('G', [0xF0, 0x80, 0xB0, 0x90, 0xF0]),
+    ('H', [0x90, 0x90, 0xF0, 0x90, 0x90]),
+    ('I', [0xE0, 0x40, 0x40, 0x40, 0xE0]),
+    ('J', [0x70, 0x20, 0x20, 0xA0, 0xE0]),
+    ('K', [0x90, 0xA0, 0xC0, 0xA0, 0x90]),
+    ('L', [0x80, 0x80, 0x80, 0x80, 0xF0]),
+    ('M', [0x90, 0xF0, 0xF0, 0x90, 0x90]),
+    ('N', [0x90, 0xD0, 0xB0, 0x90, 0x90]),
+    ('O', [0xF0, 0x90, 0x90, 0x90, 0xF0]),
+    ('P', [0xF0, 0x90, 0xF0, 0x80, 0x80]),
+    ('Q', [0xF0, 0x90, 0x90, 0xB0, 0xF0]),
+    ('R', [0xF0, 0x90, 0xF0, 0xA0, 0x90]),
+    ('S', [0xF0, 0x80, 0xF0, 0x10, 0xF0]),
+    ('T', [0xE0, 0x40, 0x40, 0x40, 0x40]),
+    ('U', [0x90, 0x90, 0x90, 0x90, 0xF0]),
+    ('V', [0x90, 0x90, 0x90, 0xA0, 0x40]),
+    ('W', [0x90, 0x90, 0xF0, 0xF0, 0x90]),
+    ('X', [0x90, 0x90, 0x60, 0x90, 0x90]),
+    ('Y', [0xA0, 0xA0, 0x40, 0x40, 0x40]),
+    ('Z', [0xF0, 0x10, 0x60, 0x80, 0xF0]),
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('%', [0x90, 0x10, 0x60, 0x80, 0x90]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x40]),
+    (':', [0x00, 0x40, 0x00, 0x40, 0x00]),
+    ('-', [0x00, 0x00, 0xF0, 0x00, 0x00]),
+    ('/', [0x10, 0x10, 0x20, 0x40, 0x80]),
+    ('!', [0x40, 0x40, 0x40, 0x00, 0x40]),
+    ('_', [0x00, 0x00, 0x00, 0x00, 0xF0]),
+    ('(', [0x20, 0x40, 0x40, 0x40, 0x20]),
+    (')', [0x40, 0x20, 0x20, 0x20, 0x40]),
+    ('[', [0x60, 0x40, 0x40, 0x40, 0x60]),
+    (']', [0x60, 0x20, 0x20, 0x20, 0x60]),
+    (',', [0x00, 0x00, 0x00, 0x20, 0x40]),
+    ('+', [0x00, 0x40, 0xE0, 0x40, 0x00]),
+    ('\'', [0x40, 0x40, 0x00, 0x00, 0x00]),
+    ('&', [0x40, 0xA0, 0x40, 0xA0, 0x50]),
+];
+
+/// The glyph for `c`, case insensitive; unknown characters are drawn as a
+/// filled box.
+fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    if let Some(digit) = c.to_digit(16) {
+        return FONTS[digit as usize];
+    }
+    EXTRA_GLYPHS
+        .iter()
+        .find(|g| g.0 == c)
+        .map_or([0xF0; 5], |g| g.1)
+}
+
+/// Width of `text` in font pixels.
+pub fn text_width(text: &str) -> u32 {
+    (text.chars().count() as u32 * ADVANCE).saturating_sub(1)
+}
+
+/// Font pixels lit to draw `text`, as (x, y) from its top left corner.
+pub fn text_pixels(text: &str) -> Vec<(u32, u32)> {
+    let mut pixels = Vec::new();
+    for (idx, c) in text.chars().enumerate() {
+        for (y, row) in glyph(c).iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row & (0x80 >> x) != 0 {
+                    pixels.push((idx as u32 * ADVANCE + x, y as u32));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// Text drawn over the game image: a transient message, a status shown for
+/// as long as it holds (such as pausing), and an optional speed counter.
+#[derive(Default)]
+pub struct Osd {
+    message: Option<(String, Instant)>,
+    status: Option<String>,
+    counter: Option<String>,
+}
+
+impl Osd {
+    pub fn show_message(&mut self, message: &str) {
+        self.message = Some((message.to_string(), Instant::now() + MESSAGE_TIME));
+    }
+
+    pub fn set_status(&mut self, status: Option<String>) {
+        self.status = status;
+    }
+
+    pub fn set_counter(&mut self, counter: Option<String>) {
+        self.counter = counter;
+    }
+
+    /// Drops the message once its time is up, returning whether it did, in
+    /// which case the screen needs to be drawn again.
+    pub fn expire(&mut self) -> bool {
+        match self.message {
+            Some((_, until)) if until <= Instant::now() => {
+                self.message = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The bottom left line: the message, or else the status.
+    pub fn bottom(&self) -> Option<&str> {
+        self.message
+            .as_ref()
+            .map(|m| m.0.as_str())
+            .or(self.status.as_deref())
+    }
+
+    /// The top right line.
+    pub fn top(&self) -> Option<&str> {
+        self.counter.as_deref()
+    }
+}
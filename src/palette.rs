@@ -0,0 +1,156 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use config::Config;
+
+pub type Rgb = (u8, u8, u8);
+
+/// Colors for each pixel value of the framebuffer. Entry 0 is the
+/// background and entry 1 the plain CHIP-8 foreground; the XO-CHIP plane
+/// combinations use entries 2 and 3 (or up to 15), and fall back to entry 1
+/// when the palette is shorter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<Rgb>,
+}
+
+const BUILTIN: [(&str, [Rgb; 4]); 6] = [
+    (
+        "mono",
+        [(0, 0, 0), (255, 255, 255), (170, 170, 170), (85, 85, 85)],
+    ),
+    (
+        "green",
+        [(8, 24, 8), (51, 255, 102), (20, 160, 60), (150, 255, 180)],
+    ),
+    (
+        "amber",
+        [(26, 15, 0), (255, 176, 0), (170, 100, 0), (255, 220, 120)],
+    ),
+    (
+        "lcd",
+        [(155, 188, 15), (15, 56, 15), (48, 98, 48), (139, 172, 15)],
+    ),
+    (
+        "high-contrast",
+        [(0, 0, 0), (255, 255, 0), (0, 255, 255), (255, 255, 255)],
+    ),
+    // Okabe-Ito colors, distinguishable with the common color vision
+    // deficiencies
+    (
+        "colorblind",
+        [(0, 0, 0), (230, 159, 0), (86, 180, 233), (240, 228, 66)],
+    ),
+];
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::builtin().remove(0)
+    }
+}
+
+impl Palette {
+    pub fn builtin() -> Vec<Palette> {
+        BUILTIN
+            .iter()
+            .map(|&(name, colors)| Palette {
+                name: name.to_string(),
+                colors: colors.to_vec(),
+            })
+            .collect()
+    }
+
+    pub fn color(&self, pixel: u8) -> Rgb {
+        match self.colors.get(pixel as usize) {
+            Some(&color) => color,
+            None => self.colors[1],
+        }
+    }
+
+    /// The `palette` entry of the `[display]` config sections, per-ROM
+    /// sections winning over the global one.
+    pub fn from_config(
+        config: &Config,
+        rom_name: &str,
+        rom_hash: &str,
+    ) -> io::Result<Option<Palette>> {
+        let mut palette = None;
+        for entry in config.entries("display", rom_name, rom_hash) {
+            if entry.key == "palette" {
+                palette = Some(
+                    entry
+                        .value
+                        .parse()
+                        .map_err(|e: String| config.error(entry, &e))?,
+                );
+            }
+        }
+        Ok(palette)
+    }
+
+    /// The built-in palettes with `self` selected, returned as the list and
+    /// the index of `self` in it, for frontends that cycle through them.
+    pub fn cycle(self) -> (Vec<Palette>, usize) {
+        let mut palettes = Palette::builtin();
+        match palettes.iter().position(|p| *p == self) {
+            Some(idx) => (palettes, idx),
+            None => {
+                palettes.insert(0, self);
+                (palettes, 0)
+            }
+        }
+    }
+}
+
+fn parse_color(s: &str) -> Result<Rgb, String> {
+    let hex = s.trim().trim_start_matches('#');
+    let value = if hex.len() == 6 {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        None
+    };
+    match value {
+        Some(v) => Ok(((v >> 16) as u8, (v >> 8) as u8, v as u8)),
+        None => Err(format!("invalid color `{}` (expected #rrggbb)", s.trim())),
+    }
+}
+
+/// Parses either the name of a built-in palette or a comma separated list
+/// of 2, 4 or 16 `#rrggbb` colors, background first.
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(palette) = Palette::builtin().into_iter().find(|p| p.name == s) {
+            return Ok(palette);
+        }
+        if !s.contains(',') {
+            let names = BUILTIN.iter().map(|p| p.0).collect::<Vec<_>>();
+            return Err(format!(
+                "unknown palette `{}` (expected one of {} or a list of #rrggbb colors)",
+                s,
+                names.join(", ")
+            ));
+        }
+        let colors = s
+            .split(',')
+            .map(parse_color)
+            .collect::<Result<Vec<_>, _>>()?;
+        match colors.len() {
+            2 | 4 | 16 => Ok(Palette {
+                name: "custom".to_string(),
+                colors,
+            }),
+            n => Err(format!("a palette needs 2, 4 or 16 colors, got {}", n)),
+        }
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
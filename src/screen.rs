@@ -1,56 +1,353 @@
 use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::render::{BlendMode, Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
 use sdl2::Sdl;
+use std::time::{Duration, Instant};
 
+use browser::MenuLine;
+use filter::Frame;
+use frontend::{Display, Hotkey, Status};
+use osd::{self, Osd};
+use palette::Palette;
+
+/// Draws the framebuffer by uploading it to a streaming texture the size of
+/// the CHIP-8 display, which the renderer then scales to the window.
 pub struct Screen {
     canvas: Canvas<Window>,
+    texture: Texture,
+    palettes: Vec<Palette>,
+    palette: usize,
+    last_frame: Frame,
+    refresh_interval: Duration,
+    title: String,
+    integer_scale: bool,
+    osd: Osd,
+    show_fps: bool,
+    fps: u32,
+    fps_window: (Instant, u32),
 }
 
 use CHIP8_HEIGHT;
 use CHIP8_WIDTH;
 
-pub const SCALE: u32 = 20;
-pub const SCREEN_WIDTH: u32 = (CHIP8_WIDTH as u32) * SCALE;
-pub const SCREEN_HEIGHT: u32 = (CHIP8_HEIGHT as u32) * SCALE;
+pub const DEFAULT_SCALE: u32 = 20;
+
+/// Used when SDL cannot tell the refresh rate of the display.
+const DEFAULT_REFRESH_RATE: u32 = 60;
+
+/// How the window is opened and how the display is scaled into it.
+#[derive(Clone, Debug)]
+pub struct WindowOptions {
+    /// Shown in the title bar next to the emulator status.
+    pub title: String,
+    /// Initial window size in window pixels per CHIP-8 pixel.
+    pub scale: u32,
+    /// Only scale the display by whole numbers, for evenly sized pixels.
+    pub integer_scale: bool,
+    pub fullscreen: bool,
+    /// Show frames and instructions per second over the game.
+    pub show_fps: bool,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        WindowOptions {
+            title: "CHIP-8".to_string(),
+            scale: DEFAULT_SCALE,
+            integer_scale: false,
+            fullscreen: false,
+            show_fps: false,
+        }
+    }
+}
+
+/// The largest rectangle with the aspect ratio of the CHIP-8 display that
+/// fits centered in an `width` x `height` output; the rest is letterboxed.
+fn display_rect(width: u32, height: u32, integer_scale: bool) -> Rect {
+    let (w, h) = (CHIP8_WIDTH as u32, CHIP8_HEIGHT as u32);
+    let (dst_w, dst_h) = if integer_scale {
+        let scale = (width / w).min(height / h).max(1);
+        (w * scale, h * scale)
+    } else if width * h > height * w {
+        (height * w / h, height)
+    } else {
+        (width, width * h / w)
+    };
+    Rect::new(
+        (width as i32 - dst_w as i32) / 2,
+        (height as i32 - dst_h as i32) / 2,
+        dst_w,
+        dst_h,
+    )
+}
 
 impl Screen {
-    pub fn new(sdl_context: &Sdl) -> Self {
+    /// Opens the window drawing with `palette`; the palette hotkey cycles
+    /// through the built-in ones from there.
+    pub fn new(sdl_context: &Sdl, palette: Palette, options: WindowOptions) -> Self {
         let video_sys = sdl_context.video().unwrap();
-        let window = video_sys
-            .window("CHIP-8", SCREEN_WIDTH, SCREEN_HEIGHT)
+        let scale = options.scale.max(1);
+        let mut window = video_sys
+            .window(
+                &options.title,
+                CHIP8_WIDTH as u32 * scale,
+                CHIP8_HEIGHT as u32 * scale,
+            )
             .position_centered()
+            .resizable()
             .opengl()
             .build()
             .unwrap();
+        if options.fullscreen {
+            let _ = window.set_fullscreen(FullscreenType::Desktop);
+        }
+
+        let refresh_rate = window
+            .display_index()
+            .and_then(|idx| video_sys.current_display_mode(idx))
+            .ok()
+            .map(|mode| mode.refresh_rate)
+            .filter(|&rate| rate > 0)
+            .map_or(DEFAULT_REFRESH_RATE, |rate| rate as u32);
 
         let mut canvas = window.into_canvas().build().unwrap();
+        let texture = canvas
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                CHIP8_WIDTH as u32,
+                CHIP8_HEIGHT as u32,
+            )
+            .unwrap();
 
+        let (palettes, palette) = palette.cycle();
         canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
         canvas.clear();
         canvas.present();
 
-        Screen { canvas }
+        Screen {
+            canvas,
+            texture,
+            palettes,
+            palette,
+            last_frame: Frame::default(),
+            refresh_interval: Duration::from_secs(1) / refresh_rate,
+            title: options.title,
+            integer_scale: options.integer_scale,
+            osd: Osd::default(),
+            show_fps: options.show_fps,
+            fps: 0,
+            fps_window: (Instant::now(), 0),
+        }
     }
 
-    pub fn draw(&mut self, pixels: &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT]) {
-        for (i, row) in pixels.iter().enumerate() {
-            for (j, p) in row.iter().enumerate() {
-                let x = (j as u32) * SCALE;
-                let y = (i as u32) * SCALE;
+    /// Draws with `palette` from now on, e.g. that of another ROM.
+    pub fn set_palette(&mut self, palette: Palette) {
+        let (palettes, palette) = palette.cycle();
+        self.palettes = palettes;
+        self.palette = palette;
+    }
 
-                self.canvas.set_draw_color(if *p == 0 {
-                    pixels::Color::RGB(0, 0, 0)
-                } else {
-                    pixels::Color::RGB(255, 255, 255)
-                });
+    /// Shown in the title bar next to the emulator status.
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+        let _ = self.canvas.window_mut().set_title(title);
+    }
 
-                let _ = self
-                    .canvas
-                    .fill_rect(Rect::new(x as i32, y as i32, SCALE, SCALE));
-            }
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        let _ = window.set_fullscreen(state);
+    }
+
+    pub fn draw(&mut self, frame: &Frame) {
+        let palette = &self.palettes[self.palette];
+        let _ = self
+            .texture
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                for i in 0..CHIP8_HEIGHT {
+                    for j in 0..CHIP8_WIDTH {
+                        let (r, g, b) = frame.color(palette, j, i);
+                        let offset = i * pitch + j * 3;
+                        buffer[offset] = r;
+                        buffer[offset + 1] = g;
+                        buffer[offset + 2] = b;
+                    }
+                }
+            });
+
+        let (width, height) = self.canvas.output_size().unwrap_or((0, 0));
+        let rect = display_rect(width, height, self.integer_scale);
+        self.canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        let _ = self.canvas.copy(&self.texture, None, rect);
+        self.draw_osd(rect);
+        self.canvas.present();
+        self.last_frame = *frame;
+
+        let (since, presents) = self.fps_window;
+        if since.elapsed() >= Duration::from_secs(1) {
+            self.fps = presents + 1;
+            self.fps_window = (Instant::now(), 0);
+        } else {
+            self.fps_window = (since, presents + 1);
+        }
+    }
+
+    /// Draws lines of text in the palette colors instead of the game, the
+    /// highlighted ones inverted, for the ROM browser.
+    pub fn draw_menu(&mut self, lines: &[MenuLine]) {
+        let (width, height) = self.canvas.output_size().unwrap_or((0, 0));
+        let area = display_rect(width, height, self.integer_scale);
+        let line_height = osd::GLYPH_HEIGHT + 2;
+        let pixel = (area.height() / (lines.len() as u32 * line_height + 2)).max(1);
+        let columns = (area.width() / pixel).saturating_sub(2) / osd::ADVANCE;
+
+        let palette = &self.palettes[self.palette];
+        let color = |idx: u8| {
+            let (r, g, b) = palette.color(idx);
+            pixels::Color::RGB(r, g, b)
+        };
+        let (background, foreground) = (color(0), color(1));
+        self.canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        self.canvas.set_draw_color(background);
+        let _ = self.canvas.fill_rect(area);
+
+        for (row, &(ref text, highlighted)) in lines.iter().enumerate() {
+            let text = text.chars().take(columns as usize).collect::<String>();
+            let x = area.left() + pixel as i32;
+            let y = area.top() + ((row as u32 * line_height + 1) * pixel) as i32;
+            let ink = if highlighted {
+                self.canvas.set_draw_color(foreground);
+                let _ = self.canvas.fill_rect(Rect::new(
+                    x,
+                    y,
+                    area.width().saturating_sub(2 * pixel),
+                    line_height * pixel,
+                ));
+                background
+            } else {
+                foreground
+            };
+            self.canvas.set_draw_color(ink);
+            let rects = osd::text_pixels(&text)
+                .into_iter()
+                .map(|(px, py)| {
+                    Rect::new(
+                        x + ((px + 1) * pixel) as i32,
+                        y + ((py + 1) * pixel) as i32,
+                        pixel,
+                        pixel,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let _ = self.canvas.fill_rects(&rects);
         }
         self.canvas.present();
     }
+
+    fn redraw(&mut self) {
+        let frame = self.last_frame;
+        self.draw(&frame);
+    }
+
+    /// Draws the OSD lines on dark boxes in the corners of `area`, with
+    /// font pixels scaled to the size of the game image.
+    fn draw_osd(&mut self, area: Rect) {
+        let pixel = (area.height() / 96).max(1);
+        let margin = 2 * pixel;
+        let box_height = (osd::GLYPH_HEIGHT + 2) * pixel;
+        let mut lines = Vec::new();
+        if let Some(text) = self.osd.bottom() {
+            let y = area.bottom() - (margin + box_height) as i32;
+            lines.push((text.to_string(), area.left() + margin as i32, y));
+        }
+        if let Some(text) = self.osd.top() {
+            let width = (osd::text_width(text) + 2) * pixel;
+            let x = area.right() - (margin + width) as i32;
+            lines.push((text.to_string(), x, area.top() + margin as i32));
+        }
+
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        for (text, x, y) in lines {
+            let width = (osd::text_width(&text) + 2) * pixel;
+            self.canvas
+                .set_draw_color(pixels::Color::RGBA(0, 0, 0, 160));
+            let _ = self.canvas.fill_rect(Rect::new(x, y, width, box_height));
+            self.canvas
+                .set_draw_color(pixels::Color::RGB(255, 255, 255));
+            let rects = osd::text_pixels(&text)
+                .into_iter()
+                .map(|(px, py)| {
+                    Rect::new(
+                        x + ((px + 1) * pixel) as i32,
+                        y + ((py + 1) * pixel) as i32,
+                        pixel,
+                        pixel,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let _ = self.canvas.fill_rects(&rects);
+        }
+        self.canvas.set_blend_mode(BlendMode::None);
+    }
+}
+
+impl Display for Screen {
+    fn present(&mut self, frame: &Frame) {
+        self.draw(frame);
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.refresh_interval)
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::NextPalette => {
+                self.palette = (self.palette + 1) % self.palettes.len();
+                let message = format!("palette {}", self.palettes[self.palette]);
+                self.osd.show_message(&message);
+            }
+            Hotkey::ToggleFullscreen => self.toggle_fullscreen(),
+            _ => {}
+        }
+        self.redraw();
+    }
+
+    fn show_status(&mut self, status: &Status) {
+        let title = format!("{} - {}", self.title, status);
+        let _ = self.canvas.window_mut().set_title(&title);
+
+        self.osd.set_status(if status.paused {
+            Some("paused".to_string())
+        } else if status.turbo {
+            Some("fast-forward".to_string())
+        } else {
+            None
+        });
+        if self.show_fps {
+            self.osd.set_counter(Some(format!(
+                "{} fps {} ips",
+                self.fps, status.instructions_per_second
+            )));
+        }
+        self.redraw();
+    }
+
+    fn show_message(&mut self, message: &str) {
+        self.osd.show_message(message);
+        self.redraw();
+    }
+
+    fn update(&mut self) {
+        if self.osd.expire() {
+            self.redraw();
+        }
+    }
 }
@@ -0,0 +1,345 @@
+use libc;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use filter::Frame;
+use frontend::{Beeper, Display, Hotkey, Input, Poll, Status};
+use keyboard::Bindings;
+use palette::{Palette, Rgb};
+use CHIP8_HEIGHT;
+
+const ESC: u8 = 0x1B;
+
+/// Terminals, especially over SSH, cannot keep up with more redraws than
+/// this.
+const MAX_FPS: u32 = 30;
+const CTRL_C: u8 = 0x03;
+
+/// How pixels are packed into terminal cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Glyphs {
+    /// `▀` with separate foreground and background colors, 1x2 pixels per
+    /// cell.
+    HalfBlock,
+    /// Unicode Braille patterns, 2x4 pixels per cell.
+    Braille,
+}
+
+impl FromStr for Glyphs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half" => Ok(Glyphs::HalfBlock),
+            "braille" => Ok(Glyphs::Braille),
+            _ => Err(format!(
+                "unknown glyph set: {} (expected half or braille)",
+                s
+            )),
+        }
+    }
+}
+
+/// Draws the framebuffer on stdout using the alternate screen.
+pub struct TerminalDisplay {
+    glyphs: Glyphs,
+    palettes: Vec<Palette>,
+    palette: usize,
+    last_frame: Frame,
+    out: io::Stdout,
+}
+
+impl TerminalDisplay {
+    pub fn new(glyphs: Glyphs, palette: Palette) -> Self {
+        let mut out = io::stdout();
+        // switch to the alternate screen and hide the cursor
+        let _ = write!(out, "\x1b[?1049h\x1b[?25l\x1b[2J");
+        let _ = out.flush();
+        let (palettes, palette) = palette.cycle();
+        TerminalDisplay {
+            glyphs,
+            palettes,
+            palette,
+            last_frame: Frame::default(),
+            out,
+        }
+    }
+
+    /// Replaces the line below the frame with `text`.
+    fn status_line(&mut self, text: &str) {
+        let rows = match self.glyphs {
+            Glyphs::HalfBlock => CHIP8_HEIGHT / 2,
+            Glyphs::Braille => CHIP8_HEIGHT / 4,
+        };
+        let _ = write!(self.out, "\x1b[{};1H\x1b[0m\x1b[2K{}", rows + 1, text);
+        let _ = self.out.flush();
+    }
+
+    fn color(&self, pixel: u8) -> Rgb {
+        self.palettes[self.palette].color(pixel)
+    }
+
+    /// Renders rows of colors of any width and height, so hires
+    /// framebuffers work the same way as the 64x32 one.
+    pub fn render<R: AsRef<[Rgb]>>(&self, rows: &[R]) -> String {
+        let height = rows.len();
+        let width = rows.first().map_or(0, |row| row.as_ref().len());
+        let background = self.color(0);
+        let pixel = |x: usize, y: usize| {
+            if y < height && x < width {
+                rows[y].as_ref()[x]
+            } else {
+                background
+            }
+        };
+
+        let mut frame = String::from("\x1b[H");
+        match self.glyphs {
+            Glyphs::HalfBlock => {
+                for y in (0..height).step_by(2) {
+                    let mut last = None;
+                    for x in 0..width {
+                        let colors = (pixel(x, y), pixel(x, y + 1));
+                        if last != Some(colors) {
+                            let ((fr, fg, fb), (br, bg, bb)) = colors;
+                            frame.push_str(&format!(
+                                "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                                fr, fg, fb, br, bg, bb
+                            ));
+                            last = Some(colors);
+                        }
+                        frame.push('▀');
+                    }
+                    frame.push_str("\x1b[0m\r\n");
+                }
+            }
+            Glyphs::Braille => {
+                // dot bit for each (x, y) offset inside a 2x4 cell
+                const DOTS: [[u32; 2]; 4] =
+                    [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+                // Braille cells have a single foreground color, so planes
+                // beyond the first all share it
+                let (fr, fg, fb) = self.color(1);
+                let (br, bg, bb) = self.color(0);
+                for y in (0..height).step_by(4) {
+                    frame.push_str(&format!(
+                        "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                        fr, fg, fb, br, bg, bb
+                    ));
+                    for x in (0..width).step_by(2) {
+                        let mut bits = 0;
+                        for (dy, row) in DOTS.iter().enumerate() {
+                            for (dx, bit) in row.iter().enumerate() {
+                                if pixel(x + dx, y + dy) != background {
+                                    bits |= bit;
+                                }
+                            }
+                        }
+                        frame.push(::std::char::from_u32(0x2800 + bits).unwrap_or(' '));
+                    }
+                    frame.push_str("\x1b[0m\r\n");
+                }
+            }
+        }
+        frame
+    }
+}
+
+impl Display for TerminalDisplay {
+    fn present(&mut self, frame: &Frame) {
+        let text = self.render(&frame.rows(&self.palettes[self.palette]));
+        let _ = self.out.write_all(text.as_bytes());
+        let _ = self.out.flush();
+        self.last_frame = *frame;
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1) / MAX_FPS)
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        if hotkey == Hotkey::NextPalette {
+            self.palette = (self.palette + 1) % self.palettes.len();
+            let frame = self.last_frame;
+            self.present(&frame);
+        }
+    }
+
+    fn show_status(&mut self, status: &Status) {
+        self.status_line(&status.to_string());
+    }
+
+    fn show_message(&mut self, message: &str) {
+        self.status_line(message);
+    }
+}
+
+impl Drop for TerminalDisplay {
+    fn drop(&mut self) {
+        let _ = write!(self.out, "\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = self.out.flush();
+    }
+}
+
+/// Puts stdin into raw, non-blocking mode for as long as it is alive.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            // reads return immediately, with or without input
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Reads keys from stdin using the same bindings as the SDL `Keyboard`.
+/// Only keycode bindings that type a character can be used here.
+///
+/// Terminals only report key presses (and auto-repeats), never releases, so
+/// a key counts as held until `hold` has passed without it being seen again.
+/// The hold should be longer than the terminal's initial auto-repeat delay.
+pub struct TerminalInput {
+    _raw: RawMode,
+    stdin: io::Stdin,
+    hold: Duration,
+    chars: Vec<(char, usize)>,
+    hotkey_chars: Vec<(char, Hotkey)>,
+    hotkeys: Vec<Hotkey>,
+    released_at: [Option<Instant>; 16],
+    hotkeys_released_at: Vec<(Hotkey, Instant)>,
+}
+
+impl TerminalInput {
+    pub fn new(hold: Duration, bindings: &Bindings) -> io::Result<Self> {
+        let chars = (0..16)
+            .flat_map(|key| {
+                bindings
+                    .host_keys(key)
+                    .iter()
+                    .filter_map(move |host| host.to_char().map(|c| (c, key)))
+            })
+            .collect();
+        let hotkey_chars = Hotkey::ALL
+            .iter()
+            .flat_map(|&hotkey| {
+                bindings
+                    .hotkey_keys(hotkey)
+                    .iter()
+                    .filter_map(move |host| host.to_char().map(|c| (c, hotkey)))
+            })
+            .collect();
+        Ok(TerminalInput {
+            _raw: RawMode::enable()?,
+            stdin: io::stdin(),
+            hold,
+            chars,
+            hotkey_chars,
+            hotkeys: Vec::new(),
+            released_at: [None; 16],
+            hotkeys_released_at: Vec::new(),
+        })
+    }
+}
+
+impl Input for TerminalInput {
+    fn poll(&mut self) -> Poll {
+        let now = Instant::now();
+        let mut buf = [0u8; 64];
+        let n = self.stdin.lock().read(&mut buf).unwrap_or(0);
+        let bytes = &buf[..n];
+
+        // a lone escape is the Escape key, anything longer is an escape
+        // sequence (arrows, function keys) and is ignored
+        if bytes.contains(&CTRL_C) || bytes == [ESC] {
+            return Poll::Quit;
+        }
+        let bytes = match bytes.iter().position(|&b| b == ESC) {
+            Some(pos) => &bytes[..pos],
+            None => bytes,
+        };
+
+        for &b in bytes {
+            let c = (b as char).to_ascii_lowercase();
+            for &(_, key) in self.chars.iter().filter(|&&(k, _)| k == c) {
+                self.released_at[key] = Some(now + self.hold);
+            }
+            for &(_, hotkey) in self.hotkey_chars.iter().filter(|&&(k, _)| k == c) {
+                // auto-repeats of a held hotkey are not new presses
+                let held = self.hotkeys_released_at.iter_mut().find(|h| h.0 == hotkey);
+                match held {
+                    Some(held) => held.1 = now + self.hold,
+                    None => {
+                        self.hotkeys.push(hotkey);
+                        self.hotkeys_released_at.push((hotkey, now + self.hold));
+                    }
+                }
+            }
+        }
+        self.hotkeys_released_at.retain(|&(_, at)| at > now);
+
+        let mut keys = [false; 16];
+        for (key, released_at) in keys.iter_mut().zip(self.released_at.iter_mut()) {
+            match *released_at {
+                Some(at) if at > now => *key = true,
+                _ => *released_at = None,
+            }
+        }
+        Poll::Keys(keys)
+    }
+
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        mem::take(&mut self.hotkeys)
+    }
+
+    fn held_hotkeys(&self) -> Vec<Hotkey> {
+        self.hotkeys_released_at.iter().map(|h| h.0).collect()
+    }
+}
+
+/// Rings the terminal bell whenever the beeper turns on.
+#[derive(Default)]
+pub struct Bell {
+    on: bool,
+    muted: bool,
+}
+
+impl Beeper for Bell {
+    fn set(&mut self, on: bool, _pattern: Option<&[u8; 16]>) {
+        if on && !self.on && !self.muted {
+            let mut out = io::stdout();
+            let _ = out.write_all(b"\x07");
+            let _ = out.flush();
+        }
+        self.on = on;
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        if hotkey == Hotkey::Mute {
+            self.muted = !self.muted;
+        }
+    }
+}
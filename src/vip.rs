@@ -0,0 +1,516 @@
+use std::io;
+use std::time::Duration;
+
+use cdp1802::{Bus, Cdp1802};
+use interpreter::{Backend, Fault, FrameBudget, State, TIMER_RATE};
+use CHIP8_HEIGHT;
+use CHIP8_RAM;
+use CHIP8_WIDTH;
+
+/// Size of the monitor ROM, mapped from 0x8000 on, and of the CHIP-8
+/// interpreter, which is loaded at 0x0000.
+pub const ROM_SIZE: usize = 0x200;
+
+/// Where CHIP-8 programs are loaded, right after the interpreter.
+const PROGRAM_START: usize = 0x200;
+
+/// Machine cycles per scanline of the CDP1861 display chip.
+const LINE_CYCLES: u32 = 14;
+
+/// Scanlines per 60 Hz frame.
+const FRAME_LINES: u32 = 262;
+
+const FRAME_CYCLES: u32 = LINE_CYCLES * FRAME_LINES;
+
+/// Highest number of times the CPU can be sped up by.
+const MAX_SPEEDUP: u32 = 100;
+
+/// The 128 scanlines the CDP1861 fetches by DMA, 8 bytes each.
+const FIRST_DISPLAY_LINE: u32 = 80;
+const DISPLAY_LINES: u32 = 128;
+const DMA_BYTES: u32 = 8;
+
+/// The CDP1861 requests its interrupt this many lines before the first
+/// display line, and sets EF1 this many lines before the start and the end
+/// of the display.
+const INTERRUPT_LINES: u32 = 2;
+const EF1_LINES: u32 = 4;
+
+/// Everything around the CPU of the VIP: RAM, the monitor ROM, the hex
+/// keypad and the CDP1861.
+struct Board {
+    ram: Vec<u8>,
+    monitor: Vec<u8>,
+    /// After a reset the monitor also shows at 0x0000, until an address
+    /// with A15 set is used.
+    rom_overlay: bool,
+    keyboard: [bool; 16],
+    /// Key selected by OUT 2, whose state EF3 reports.
+    key_latch: usize,
+    /// Switched on by INP 1 and off by OUT 1.
+    display_on: bool,
+    line: u32,
+}
+
+impl Bus for Board {
+    fn read(&mut self, address: u16) -> u8 {
+        let address = address as usize;
+        if address & 0x8000 != 0 {
+            self.rom_overlay = false;
+            self.monitor[address % ROM_SIZE]
+        } else if self.rom_overlay {
+            self.monitor[address % ROM_SIZE]
+        } else {
+            self.ram[address % CHIP8_RAM]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let address = address as usize;
+        if address & 0x8000 == 0 {
+            self.ram[address % CHIP8_RAM] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key_latch = (value & 0xF) as usize,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn flag(&mut self, flag: u8) -> bool {
+        match flag {
+            1 => {
+                let end = FIRST_DISPLAY_LINE + DISPLAY_LINES;
+                (FIRST_DISPLAY_LINE - EF1_LINES..FIRST_DISPLAY_LINE).contains(&self.line)
+                    || (end - EF1_LINES..end).contains(&self.line)
+            }
+            3 => self.keyboard[self.key_latch],
+            _ => false,
+        }
+    }
+}
+
+/// A COSMAC VIP running its original CHIP-8 interpreter on an emulated
+/// CDP1802, for when the behaviour of the real thing matters more than
+/// speed. Every tick is one 60 Hz frame of the CDP1861 display.
+///
+/// The interpreter and the monitor ROM, which holds the display interrupt,
+/// keypad routine and font the interpreter uses, are supplied by the user.
+/// Quirks, memory layout and fonts are those of the real interpreter, so
+/// the `Options` of `Interpreter` do not apply. As the interpreter cannot
+/// be asked whether it waits on FX0A, `State::waiting_for_key` is never
+/// set, and `State::instructions` counts CDP1802 instructions. Whatever
+/// the program does, the VIP never faults.
+///
+/// A faster VIP gets more machine cycles per frame, which it runs after the
+/// last scanline, as if the vertical blank lasted longer. It cannot be made
+/// slower than the real one.
+pub struct Vip {
+    cpu: Cdp1802,
+    board: Board,
+    interpreter: Vec<u8>,
+    /// Machine cycles into the current frame.
+    cycle: u32,
+    /// Machine cycles per frame, at least `FRAME_CYCLES`.
+    frame_cycles: u32,
+    vram: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+}
+
+impl Vip {
+    /// A VIP with 4 KB of RAM, `interpreter` at 0x0000 and `monitor` as its
+    /// ROM. It boots through the monitor like when the RUN switch is
+    /// flipped up.
+    pub fn new(interpreter: &[u8], monitor: &[u8]) -> io::Result<Vip> {
+        if interpreter.is_empty() || interpreter.len() > ROM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "a VIP CHIP-8 interpreter has at most {} bytes, not {}",
+                    ROM_SIZE,
+                    interpreter.len()
+                ),
+            ));
+        }
+        if monitor.len() != ROM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the VIP monitor ROM has {} bytes, not {}",
+                    ROM_SIZE,
+                    monitor.len()
+                ),
+            ));
+        }
+        let mut vip = Vip {
+            cpu: Cdp1802::new(),
+            board: Board {
+                ram: vec![0; CHIP8_RAM],
+                monitor: monitor.to_vec(),
+                rom_overlay: true,
+                keyboard: [false; 16],
+                key_latch: 0,
+                display_on: false,
+                line: 0,
+            },
+            interpreter: interpreter.to_vec(),
+            cycle: 0,
+            frame_cycles: FRAME_CYCLES,
+            vram: [[0; CHIP8_WIDTH]; CHIP8_HEIGHT],
+        };
+        vip.reset();
+        Ok(vip)
+    }
+
+    /// Copies the 256 bytes the display showed this frame, starting at
+    /// `base`, into the framebuffer. Returns whether it changed.
+    fn update_vram(&mut self, base: Option<u16>) -> bool {
+        let mut vram = [[0; CHIP8_WIDTH]; CHIP8_HEIGHT];
+        match base {
+            Some(base) if self.board.display_on => {
+                for (y, row) in vram.iter_mut().enumerate() {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        let address = base as usize + y * CHIP8_WIDTH / 8 + x / 8;
+                        let byte = self.board.ram[address % CHIP8_RAM];
+                        *pixel = (byte >> (7 - x % 8)) & 1;
+                    }
+                }
+            }
+            // a frame that started with the display off keeps the last one
+            None if self.board.display_on => return false,
+            _ => {}
+        }
+        let changed = vram != self.vram;
+        self.vram = vram;
+        changed
+    }
+}
+
+impl Backend for Vip {
+    fn tick(&mut self, keyboard: [bool; 16]) -> Result<State<'_>, Fault> {
+        self.board.keyboard = keyboard;
+        let mut instructions = 0;
+        let mut base = None;
+        let mut dma_line = None;
+        let display_end = FIRST_DISPLAY_LINE + DISPLAY_LINES;
+        while self.cycle < self.frame_cycles {
+            let line = self.cycle / LINE_CYCLES;
+            self.board.line = line;
+            let display_on = self.board.display_on;
+
+            // the CDP1861 steals the first cycles of every display line
+            if display_on
+                && (FIRST_DISPLAY_LINE..display_end).contains(&line)
+                && dma_line != Some(line)
+            {
+                dma_line = Some(line);
+                if line == FIRST_DISPLAY_LINE {
+                    base = Some(self.cpu.r[0]);
+                }
+                for _ in 0..DMA_BYTES {
+                    self.cpu.dma_out(&mut self.board);
+                }
+                self.cycle += DMA_BYTES;
+                continue;
+            }
+            if display_on
+                && (FIRST_DISPLAY_LINE - INTERRUPT_LINES..FIRST_DISPLAY_LINE).contains(&line)
+                && self.cpu.ie
+            {
+                self.cycle += self.cpu.interrupt();
+                continue;
+            }
+
+            if !self.cpu.idle {
+                instructions += 1;
+            }
+            self.cycle += self.cpu.step(&mut self.board);
+        }
+        self.cycle -= self.frame_cycles;
+
+        let vram_changed = self.update_vram(base);
+        Ok(State {
+            vram: &self.vram,
+            vram_changed,
+            beep: self.cpu.q,
+            waiting_for_key: false,
+            instructions,
+            end_of_frame: true,
+        })
+    }
+
+    fn vram(&self) -> &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT] {
+        &self.vram
+    }
+
+    fn check_program(&self, program: &[u8]) -> io::Result<()> {
+        if PROGRAM_START + program.len() > CHIP8_RAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ROM of {} bytes does not fit in the VIP memory at {:#X}",
+                    program.len(),
+                    PROGRAM_START
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, program: &[u8]) -> io::Result<()> {
+        self.check_program(program)?;
+        self.board.ram[PROGRAM_START..PROGRAM_START + program.len()].copy_from_slice(program);
+        Ok(())
+    }
+
+    /// Clears the RAM but for the interpreter, then resets the CPU with the
+    /// monitor mapped at 0x0000.
+    fn reset(&mut self) {
+        self.cpu = Cdp1802::new();
+        for b in self.board.ram.iter_mut() {
+            *b = 0;
+        }
+        self.board.ram[..self.interpreter.len()].copy_from_slice(&self.interpreter);
+        self.board.rom_overlay = true;
+        self.board.key_latch = 0;
+        self.board.display_on = false;
+        self.cycle = 0;
+        self.vram = [[0; CHIP8_WIDTH]; CHIP8_HEIGHT];
+    }
+
+    fn frame_delay(&self) -> Duration {
+        Duration::from_secs(1) / TIMER_RATE
+    }
+
+    fn frame_budget(&self) -> FrameBudget {
+        FrameBudget::Cycles(self.frame_cycles)
+    }
+
+    fn set_frame_budget(&mut self, budget: FrameBudget) {
+        if let FrameBudget::Cycles(n) = budget {
+            self.frame_cycles = n.clamp(FRAME_CYCLES, FRAME_CYCLES * MAX_SPEEDUP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A monitor that points the display at 0x0300 from its interrupt
+    /// routine, switches the display on and loops, with Q set if `beep`.
+    fn monitor(beep: bool) -> Vec<u8> {
+        let mut monitor = vec![0; ROM_SIZE];
+        let code: &[(usize, &[u8])] = &[
+            // R3 = 8010, SEP 3, which leaves the overlay behind
+            (0x00, &[0xF8, 0x80, 0xB3, 0xF8, 0x10, 0xA3, 0xD3]),
+            // R1 = 8041, R2 = 0FF0, SEX 2, INP 1, SEQ or REQ, BR 801F
+            (
+                0x10,
+                &[
+                    0xF8, 0x80, 0xB1, 0xF8, 0x41, 0xA1, 0xF8, 0x0F, 0xB2, 0xF8, 0xF0, 0xA2, 0xE2,
+                    0x69, 0x7A, 0x30, 0x1F,
+                ],
+            ),
+            // RET, then the interrupt routine: DEC 2, SAV, R0 = 0300, BR 8040
+            (
+                0x40,
+                &[
+                    0x70, 0x22, 0x78, 0xF8, 0x03, 0xB0, 0xF8, 0x00, 0xA0, 0x30, 0x40,
+                ],
+            ),
+        ];
+        for &(address, bytes) in code {
+            monitor[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        if beep {
+            monitor[0x1E] = 0x7B;
+        }
+        monitor
+    }
+
+    /// A monitor that hands over to the interpreter at 0x0000 with the
+    /// display on, and counts R8.0 down to zero from its interrupt routine.
+    fn booting_monitor() -> Vec<u8> {
+        let mut monitor = vec![0; ROM_SIZE];
+        let code: &[(usize, &[u8])] = &[
+            // R3 = 8010, SEP 3
+            (0x00, &[0xF8, 0x80, 0xB3, 0xF8, 0x10, 0xA3, 0xD3]),
+            // R1 = 8040, R2 = 0ECF, SEX 2, INP 1, LBR 0000
+            (
+                0x10,
+                &[
+                    0xF8, 0x80, 0xB1, 0xF8, 0x40, 0xA1, 0xF8, 0x0E, 0xB2, 0xF8, 0xCF, 0xA2, 0xE2,
+                    0x69, 0xC0, 0x00, 0x00,
+                ],
+            ),
+            // LDXA, RET, then the interrupt routine: DEC 2, SAV, DEC 2, STR 2,
+            // R0 = 0300, GLO 8, BZ 804E, DEC 8, BR 803E
+            (
+                0x3E,
+                &[
+                    0x72, 0x70, 0x22, 0x78, 0x22, 0x52, 0xF8, 0x03, 0xB0, 0xF8, 0x00, 0xA0, 0x88,
+                    0x32, 0x4E, 0x28, 0x30, 0x3E,
+                ],
+            ),
+        ];
+        for &(address, bytes) in code {
+            monitor[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        monitor
+    }
+
+    /// An interpreter for 1NNN, 6XNN, ANNN, FX15 and a DXYN that only draws
+    /// at multiples of 8 pixels. The CHIP-8 PC is R5, the V registers are
+    /// at 0x0EF0, I is RA and the delay timer is R8.0.
+    #[rustfmt::skip]
+    const INTERPRETER: &[u8] = &[
+        // R5 = 0200, R6.1 = 0E
+        0xF8, 0x02, 0xB5, 0xF8, 0x00, 0xA5, 0xF8, 0x0E, 0xB6,
+        // 09: RF.0 = high byte, RF.1 = low byte, R6 = 0EF0 + X
+        0x45, 0xAF, 0x45, 0xBF, 0x8F, 0xFA, 0x0F, 0xF9, 0xF0, 0xA6,
+        // dispatch on the high nibble, and loop at 2A on anything else
+        0x8F, 0xFA, 0xF0,
+        0xFB, 0x60, 0x32, 0x2C, 0xFB, 0x90, 0x32, 0x30, 0xFB, 0x50, 0x32, 0x34,
+        0xFB, 0x70, 0x32, 0x44, 0xFB, 0xC0, 0x32, 0x3C, 0x30, 0x2A,
+        // 2C: 6XNN
+        0x9F, 0x56, 0x30, 0x09,
+        // 30: FX15
+        0x06, 0xA8, 0x30, 0x09,
+        // 34: ANNN
+        0x8F, 0xFA, 0x0F, 0xBA, 0x9F, 0xAA, 0x30, 0x09,
+        // 3C: 1NNN
+        0x8F, 0xFA, 0x0F, 0xB5, 0x9F, 0xA5, 0x30, 0x09,
+        // 44: DXYN, M(R2) = VX / 8, R7 = 0EF0 + Y
+        0x06, 0xF6, 0xF6, 0xF6, 0x52,
+        0x9F, 0xF6, 0xF6, 0xF6, 0xF6, 0xF9, 0xF0, 0xA7, 0xF8, 0x0E, 0xB7,
+        // R7 = 0300 + VY * 8 + VX / 8, R9 = I, RE.0 = N
+        0x07, 0xFE, 0xFE, 0xFE, 0xF4, 0xA7, 0xF8, 0x03, 0xB7,
+        0x8A, 0xA9, 0x9A, 0xB9, 0x9F, 0xFA, 0x0F, 0xAE,
+        // 65: until RE.0 is zero, M(R7) ^= M(R9++), R7 += 8, RE -= 1
+        0x8E, 0x32, 0x09, 0xE7, 0x49, 0xF3, 0x57, 0xE2,
+        0x87, 0xFC, 0x08, 0xA7, 0x2E, 0x30, 0x65,
+    ];
+
+    /// A VIP running `program` with the test monitor and an interpreter
+    /// that is never run.
+    fn vip(program: &[u8], beep: bool) -> Vip {
+        let mut vip = Vip::new(&[0xFF; 0x40], &monitor(beep)).unwrap();
+        vip.load(program).unwrap();
+        vip
+    }
+
+    #[test]
+    fn shows_the_page_the_interrupt_points_at() {
+        let mut program = vec![0; 0x200];
+        program[0x100] = 0x80;
+        program[0x1FF] = 0x01;
+        let mut vip = vip(&program, false);
+
+        let state = vip.tick([false; 16]).unwrap();
+        assert!(state.vram_changed && state.end_of_frame && !state.beep);
+        let lit = state
+            .vram
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, &p)| (x, y, p)))
+            .filter(|&(_, _, p)| p != 0)
+            .map(|(x, y, _)| (x, y))
+            .collect::<Vec<_>>();
+        assert_eq!(lit, vec![(0, 0), (63, 31)]);
+
+        assert!(!vip.tick([false; 16]).unwrap().vram_changed);
+        // the interpreter in RAM survives a reset, the program does not
+        vip.reset();
+        assert_eq!(vip.board.ram[0], 0xFF);
+        assert_eq!(vip.board.ram[0x300], 0);
+    }
+
+    #[test]
+    fn beeps_while_q_is_set() {
+        assert!(!vip(&[], false).tick([false; 16]).unwrap().beep);
+        assert!(vip(&[], true).tick([false; 16]).unwrap().beep);
+    }
+
+    #[test]
+    fn runs_more_cycles_per_frame_when_faster() {
+        let mut vip = vip(&[], false);
+        vip.tick([false; 16]).unwrap();
+        let normal = vip.tick([false; 16]).unwrap().instructions;
+
+        vip.set_frame_budget(FrameBudget::Cycles(FRAME_CYCLES * 2));
+        assert_eq!(vip.frame_budget(), FrameBudget::Cycles(FRAME_CYCLES * 2));
+        let faster = vip.tick([false; 16]).unwrap().instructions;
+        assert_eq!(faster, normal + FRAME_CYCLES / 2);
+
+        // it cannot run slower than the real one
+        vip.set_frame_budget(FrameBudget::Cycles(FRAME_CYCLES / 2));
+        assert_eq!(vip.frame_budget(), FrameBudget::Cycles(FRAME_CYCLES));
+        vip.set_frame_budget(FrameBudget::Instructions(10));
+        assert_eq!(vip.frame_budget(), FrameBudget::Cycles(FRAME_CYCLES));
+    }
+
+    #[test]
+    fn rejects_programs_that_do_not_fit() {
+        let vip = vip(&[], false);
+        assert!(vip.check_program(&[0; CHIP8_RAM - PROGRAM_START]).is_ok());
+        assert!(vip
+            .check_program(&[0; CHIP8_RAM - PROGRAM_START + 1])
+            .is_err());
+        assert!(Vip::new(&[], &monitor(false)).is_err());
+        assert!(Vip::new(&[0; 0x10], &[0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn runs_chip8_programs_through_the_interpreter() {
+        let mut vip = Vip::new(INTERPRETER, &booting_monitor()).unwrap();
+        vip.load(&[
+            0x60, 0x03, // V0 = 3
+            0xF0, 0x15, // DT = V0
+            0x61, 0x08, // V1 = 8
+            0x62, 0x02, // V2 = 2
+            0xA2, 0x10, // I = 210
+            0xD1, 0x23, // draw 3 rows at (8, 2)
+            0x12, 0x0C, // loop
+            0x00, 0x00, 0xF0, 0x90, 0xF0,
+        ])
+        .unwrap();
+
+        let mut timer = vec![];
+        for _ in 0..5 {
+            let state = vip.tick([false; 16]).unwrap();
+            assert!(state.end_of_frame);
+            timer.push(vip.cpu.r[8]);
+        }
+        // FX15 runs before the first interrupt, which already counts once
+        assert_eq!(timer, vec![2, 1, 0, 0, 0]);
+        assert_eq!(&vip.board.ram[0xEF0..0xEF3], &[3, 8, 2]);
+
+        let lit = vip
+            .vram()
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, &p)| (x, y, p)))
+            .filter(|&(_, _, p)| p != 0)
+            .map(|(x, y, _)| (x, y))
+            .collect::<Vec<_>>();
+        let mut sprite = vec![];
+        for &(y, row) in &[(2, 0xF0u8), (3, 0x90), (4, 0xF0)] {
+            sprite.extend(
+                (0..8)
+                    .filter(|x| row & (0x80 >> x) != 0)
+                    .map(|x| (8 + x, y)),
+            );
+        }
+        assert_eq!(lit, sprite);
+    }
+}
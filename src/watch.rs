@@ -0,0 +1,59 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use interpreter::MemoryLayout;
+
+/// How often the ROM file is looked at.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Notices when the ROM file is written to, for `--watch`.
+pub struct RomWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    layout: MemoryLayout,
+    /// Patch the new program into memory instead of restarting.
+    pub keep_state: bool,
+}
+
+impl RomWatcher {
+    /// Watches the ROM at `path`, which has to fit in `layout`.
+    pub fn new<P: Into<PathBuf>>(path: P, keep_state: bool, layout: MemoryLayout) -> Self {
+        let path = path.into();
+        RomWatcher {
+            modified: fs::metadata(&path).and_then(|m| m.modified()).ok(),
+            path,
+            last_check: Instant::now(),
+            layout,
+            keep_state,
+        }
+    }
+
+    /// The new program once the file changed, or why it cannot be loaded.
+    /// Files that cannot be read are tried again at the next change.
+    pub fn poll(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        let program = match fs::read(&self.path) {
+            Ok(program) => program,
+            Err(e) => return Some(Err(e)),
+        };
+        if program.is_empty() {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ROM is empty",
+            )));
+        }
+        Some(self.layout.check_program(&program).map(|_| program))
+    }
+}
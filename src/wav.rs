@@ -0,0 +1,157 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use audio::{Oscillator, Tone};
+use frontend::{Beeper, Hotkey};
+use interpreter::TIMER_RATE;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Samples rendered per emulated 60 Hz frame, a whole number so that the
+/// audio stays in step with the sound timer however long the run is.
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / TIMER_RATE) as usize;
+
+/// Size of the RIFF and fmt headers up to the first sample.
+const HEADER_SIZE: u32 = 44;
+
+/// Renders the beeper to a 16 bit mono WAV file, one frame worth of samples
+/// at the end of every emulated frame, with the tone as it was last set.
+/// XO-CHIP audio patterns are not rendered, as no backend plays them.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    oscillator: Oscillator,
+    buffer: Vec<f32>,
+    samples: u32,
+    muted: bool,
+    error: Option<io::Error>,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, tone: Tone) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // the sizes are filled in by `finish`
+        write_header(&mut out, 0)?;
+        Ok(WavWriter {
+            out,
+            oscillator: Oscillator::new(tone, SAMPLE_RATE),
+            buffer: vec![0.0; SAMPLES_PER_FRAME],
+            samples: 0,
+            muted: tone.muted,
+            error: None,
+        })
+    }
+}
+
+fn write_header<W: Write>(out: &mut W, samples: u32) -> io::Result<()> {
+    let data_size = samples * 2;
+    out.write_all(b"RIFF")?;
+    out.write_u32::<LittleEndian>(HEADER_SIZE - 8 + data_size)?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_u32::<LittleEndian>(16)?;
+    // PCM, mono
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u32::<LittleEndian>(SAMPLE_RATE)?;
+    out.write_u32::<LittleEndian>(SAMPLE_RATE * 2)?;
+    out.write_u16::<LittleEndian>(2)?;
+    out.write_u16::<LittleEndian>(16)?;
+    out.write_all(b"data")?;
+    out.write_u32::<LittleEndian>(data_size)
+}
+
+impl Beeper for WavWriter {
+    fn set(&mut self, on: bool, _pattern: Option<&[u8; 16]>) {
+        self.oscillator.set_gate(on && !self.muted);
+    }
+
+    fn end_frame(&mut self) {
+        self.oscillator.fill(&mut self.buffer);
+        for &sample in &self.buffer {
+            let value = (sample * f32::from(i16::MAX)) as i16;
+            if let Err(e) = self.out.write_i16::<LittleEndian>(value) {
+                // reported by `finish`
+                self.error.get_or_insert(e);
+            }
+        }
+        self.samples += SAMPLES_PER_FRAME as u32;
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        if hotkey == Hotkey::Mute {
+            self.muted = !self.muted;
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, self.samples)?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::env;
+    use std::fs;
+
+    /// Renders `frames`, beeping during the ones set, and reads the file
+    /// back.
+    fn render(name: &str, tone: Tone, frames: &[bool]) -> Vec<u8> {
+        let path = env::temp_dir().join(format!("chip8-{}-{}.wav", name, std::process::id()));
+        let mut wav = WavWriter::create(&path, tone).unwrap();
+        for &on in frames {
+            wav.set(on, None);
+            wav.end_frame();
+        }
+        wav.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn renders_a_frame_of_samples_per_frame() {
+        let frames = [false, true, true, false, false];
+        let data = render("beep", Tone::default(), &frames);
+        let samples = SAMPLES_PER_FRAME * frames.len();
+        assert_eq!(data.len(), HEADER_SIZE as usize + samples * 2);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(LittleEndian::read_u32(&data[4..]), data.len() as u32 - 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(LittleEndian::read_u32(&data[24..]), SAMPLE_RATE);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(LittleEndian::read_u32(&data[40..]), samples as u32 * 2);
+
+        let frame = |idx: usize| {
+            let start = HEADER_SIZE as usize + idx * SAMPLES_PER_FRAME * 2;
+            data[start..start + SAMPLES_PER_FRAME * 2]
+                .chunks(2)
+                .map(LittleEndian::read_i16)
+                .collect::<Vec<_>>()
+        };
+        assert!(frame(0).iter().all(|&s| s == 0));
+        assert!(frame(1).iter().any(|&s| s > 8000));
+        assert!(frame(2).iter().any(|&s| s < -8000));
+        // the release is over well within a frame
+        assert!(frame(4).iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn muted_tones_render_silence() {
+        let tone = Tone {
+            muted: true,
+            ..Tone::default()
+        };
+        let data = render("muted", tone, &[true, true]);
+        assert_eq!(data.len(), HEADER_SIZE as usize + SAMPLES_PER_FRAME * 4);
+        assert!(data[HEADER_SIZE as usize..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,235 @@
+//! Drives the gdb stub over a local TCP connection the way gdb would.
+
+extern crate chip8;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+use chip8::gdb::Stub;
+use chip8::interpreter::{Interpreter, Options};
+
+/// The gdb end of a connection to a stub serving `program`.
+struct Client {
+    stream: TcpStream,
+    stub: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    fn connect(program: &[u8]) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut interpreter = Interpreter::with_options(Options {
+            seed: 0,
+            ..Options::default()
+        });
+        interpreter.load(program).unwrap();
+        let stub = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Stub::new(interpreter, stream).unwrap().serve().unwrap();
+        });
+        Client {
+            stream: TcpStream::connect(address).unwrap(),
+            stub: Some(stub),
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0u8];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    /// Sends `packet` and returns the acknowledgement.
+    fn send(&mut self, packet: &str) -> u8 {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.send_raw(format!("${}#{:02x}", packet, checksum).as_bytes());
+        self.read_byte()
+    }
+
+    /// Reads a packet, checks its checksum and acknowledges it.
+    fn receive(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(
+            checksum,
+            data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+        );
+        self.send_raw(b"+");
+        String::from_utf8(data).unwrap()
+    }
+
+    fn command(&mut self, packet: &str) -> String {
+        assert_eq!(self.send(packet), b'+');
+        self.receive()
+    }
+
+    /// Kills the program and waits for the stub to finish.
+    fn kill(mut self) {
+        assert_eq!(self.send("k"), b'+');
+        self.stub.take().unwrap().join().unwrap();
+    }
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> String {
+    let bytes = (0..s.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&s[idx..idx + 2], 16).unwrap())
+        .collect::<Vec<_>>();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn steps_and_reads_registers() {
+    // V0 = 2A, I = 300, V1 = V0
+    let mut client = Client::connect(&[0x60, 0x2A, 0xA3, 0x00, 0x81, 0x00]);
+    assert!(client
+        .command("qSupported:swbreak+")
+        .contains("qXfer:features:read+"));
+    assert_eq!(client.command("?"), "S05");
+    assert_eq!(client.command("p11"), "0200");
+    for _ in 0..3 {
+        assert_eq!(client.command("s"), "S05");
+    }
+    let registers = client.command("g");
+    assert_eq!(registers.len(), 46);
+    assert_eq!(&registers[..4], "2a2a");
+    assert_eq!(&registers[32..40], "03000206");
+    assert_eq!(client.command("p10"), "0300");
+    assert_eq!(client.command("p15"), "E01");
+
+    assert_eq!(client.command("P0=07"), "OK");
+    assert_eq!(client.command("P11=0200"), "OK");
+    assert_eq!(client.command("P11=02"), "E01");
+    assert_eq!(&client.command("g")[..2], "07");
+    assert_eq!(client.command("p11"), "0200");
+    client.kill();
+}
+
+#[test]
+fn stops_at_breakpoints() {
+    // 200: V0 += 1, 202: jump to 200
+    let mut client = Client::connect(&[0x70, 0x01, 0x12, 0x00]);
+    assert_eq!(client.command("Z0,202,2"), "OK");
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p11"), "0202");
+    assert_eq!(client.command("p0"), "01");
+    // continuing from the breakpoint runs past it first
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p0"), "02");
+    assert_eq!(client.command("z0,202,2"), "OK");
+    assert_eq!(client.command("Z0,200,2"), "OK");
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p11"), "0200");
+    assert_eq!(client.command("p0"), "02");
+    // watchpoints are not supported
+    assert_eq!(client.command("Z2,300,1"), "");
+    client.kill();
+}
+
+#[test]
+fn interrupts_a_running_program() {
+    // jumps to itself forever
+    let mut client = Client::connect(&[0x12, 0x00]);
+    assert_eq!(client.send("c"), b'+');
+    client.send_raw(&[0x03]);
+    assert_eq!(client.receive(), "S02");
+    assert_eq!(client.command("p11"), "0200");
+    client.kill();
+}
+
+#[test]
+fn reads_and_writes_memory() {
+    let mut client = Client::connect(&[0x12, 0x00]);
+    assert_eq!(client.command("m200,2"), "1200");
+    assert_eq!(client.command("M300,3:abcdef"), "OK");
+    assert_eq!(client.command("m300,3"), "abcdef");
+    assert_eq!(client.command("M300,2:ab"), "E01");
+    assert_eq!(client.command("MFFF,2:abcd"), "E01");
+    // reads stop at the end of memory
+    assert_eq!(client.command("mFFE,4"), "0000");
+    assert_eq!(client.command("m1000,1"), "E01");
+    client.kill();
+}
+
+#[test]
+fn reports_faults() {
+    let mut client = Client::connect(&[0x01, 0x23]);
+    assert_eq!(client.send("c"), b'+');
+    assert_eq!(
+        unhex(&client.receive()[1..]),
+        "the program stopped: unsupported instruction 0123 at $200\n"
+    );
+    assert_eq!(client.receive(), "S04");
+    assert_eq!(client.command("p11"), "0200");
+    client.kill();
+}
+
+#[test]
+fn runs_monitor_commands() {
+    // skips the jump to itself once key 5 is held
+    let mut client = Client::connect(&[0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0x00, 0xE0]);
+    assert_eq!(client.command("Z0,206,2"), "OK");
+    assert_eq!(
+        unhex(&client.command(&format!("qRcmd,{}", hex("keys 5")))),
+        "holding keys 5\n"
+    );
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p11"), "0206");
+
+    let screen = unhex(&client.command(&format!("qRcmd,{}", hex("screen"))));
+    assert_eq!(screen.lines().count(), 32);
+    assert!(screen.lines().all(|line| line == ".".repeat(64)));
+    assert_eq!(
+        unhex(&client.command(&format!("qRcmd,{}", hex("keys x")))),
+        "invalid key `x`\n"
+    );
+    client.kill();
+}
+
+#[test]
+fn serves_the_target_description() {
+    let mut client = Client::connect(&[]);
+    let mut xml = String::new();
+    loop {
+        let reply = client.command(&format!(
+            "qXfer:features:read:target.xml:{:x},80",
+            xml.len()
+        ));
+        xml.push_str(&reply[1..]);
+        if reply.starts_with('l') {
+            break;
+        }
+        assert!(reply.starts_with('m'));
+    }
+    assert!(xml.contains(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#));
+    assert_eq!(xml.matches("<reg ").count(), 21);
+    client.kill();
+}
+
+#[test]
+fn asks_again_for_damaged_packets() {
+    let mut client = Client::connect(&[]);
+    client.send_raw(b"$g#00");
+    assert_eq!(client.read_byte(), b'-');
+    assert_eq!(client.command("m200,1"), "00");
+    assert_eq!(client.send("D"), b'+');
+    assert_eq!(client.receive(), "OK");
+    client.stub.take().unwrap().join().unwrap();
+}